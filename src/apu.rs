@@ -0,0 +1,1137 @@
+use std::io;
+
+use io_device::IODevice;
+use state::{Decoder, Encoder};
+
+/// CPU clock frequency in Hz.
+const CLOCK_HZ: u32 = 4_194_304;
+/// Number of clocks between two frame sequencer steps (512 Hz).
+const FRAME_SEQUENCER_PERIOD: u16 = 8192;
+/// Default output sample rate in Hz.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// Waveforms of the square channel duty cycles (12.5%, 25%, 50% and 75%).
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// Divisors of the noise channel clock.
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Length counter that silences a channel after a given time.
+struct LengthCounter {
+    /// Whether the counter is enabled
+    enabled: bool,
+    /// Remaining length
+    counter: u16,
+    /// Maximum length (64 or 256)
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    /// Loads the length from the NRx1 register.
+    fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Clocks the counter and returns whether the channel should be silenced.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+
+        false
+    }
+
+    fn save_state(&self, enc: &mut Encoder) {
+        enc.bool(self.enabled);
+        enc.u16(self.counter);
+    }
+
+    fn load_state(&mut self, dec: &mut Decoder) -> io::Result<()> {
+        self.enabled = dec.bool()?;
+        self.counter = dec.u16()?;
+
+        Ok(())
+    }
+}
+
+/// Volume envelope.
+struct Envelope {
+    /// Initial volume
+    initial: u8,
+    /// Whether the volume increases
+    increase: bool,
+    /// Number of frame sequencer steps between volume changes
+    period: u8,
+    /// Current volume
+    volume: u8,
+    /// Steps until the next volume change
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0x08 > 0;
+        self.period = val & 0x07;
+    }
+
+    fn read(&self) -> u8 {
+        self.initial << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    /// Returns whether the DAC is on, which is the case unless the upper
+    /// five bits of NRx2 are all zero.
+    fn dac_enabled(&self) -> bool {
+        self.initial > 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, enc: &mut Encoder) {
+        enc.u8(self.read());
+        enc.u8(self.volume);
+        enc.u8(self.timer);
+    }
+
+    fn load_state(&mut self, dec: &mut Decoder) -> io::Result<()> {
+        self.write(dec.u8()?);
+        self.volume = dec.u8()?;
+        self.timer = dec.u8()?;
+
+        Ok(())
+    }
+}
+
+/// Square wave channel (channel 1 with frequency sweep, channel 2 without).
+struct Square {
+    /// Whether the channel is playing
+    enabled: bool,
+    /// Whether the channel has a frequency sweep unit
+    has_sweep: bool,
+    /// Duty cycle
+    duty: u8,
+    /// Position in the duty cycle waveform
+    duty_pos: u8,
+    /// Frequency
+    freq: u16,
+    /// Clocks until the next waveform step
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    /// Sweep period
+    sweep_period: u8,
+    /// Whether the sweep decreases the frequency
+    sweep_negate: bool,
+    /// Sweep shift
+    sweep_shift: u8,
+    /// Steps until the next sweep
+    sweep_timer: u8,
+    /// Whether the sweep unit is active
+    sweep_enabled: bool,
+    /// Frequency the sweep is calculated from
+    shadow_freq: u16,
+}
+
+impl Square {
+    fn new(has_sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            has_sweep,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_freq: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // NR10: Sweep
+            0 => {
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 > 0;
+                self.sweep_shift = val & 0x07;
+            }
+            // NRx1: Duty and length
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3f);
+            }
+            // NRx2: Envelope
+            2 => {
+                self.envelope.write(val);
+                self.enabled &= self.envelope.dac_enabled();
+            }
+            // NRx3: Frequency low
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            // NRx4: Frequency high and control
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val & 0x07) as u16) << 8;
+                self.length.enabled = val & 0x40 > 0;
+
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 if self.has_sweep => {
+                0x80 | self.sweep_period << 4 | (self.sweep_negate as u8) << 3 | self.sweep_shift
+            }
+            0 => 0xff,
+            1 => self.duty << 6 | 0x3f,
+            2 => self.envelope.read(),
+            3 => 0xff,
+            4 => 0xbf | (self.length.enabled as u8) << 6,
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = (2048 - self.freq) * 4;
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.shadow_freq = self.freq;
+            self.sweep_timer = self.sweep_reload();
+            self.sweep_enabled = self.sweep_period > 0 || self.sweep_shift > 0;
+
+            if self.sweep_shift > 0 {
+                self.sweep_freq();
+            }
+        }
+    }
+
+    /// Returns the sweep period, where 0 is treated as 8.
+    fn sweep_reload(&self) -> u8 {
+        if self.sweep_period > 0 {
+            self.sweep_period
+        } else {
+            8
+        }
+    }
+
+    /// Calculates the next sweep frequency and disables the channel on
+    /// overflow.
+    fn sweep_freq(&mut self) -> u16 {
+        let delta = self.shadow_freq >> self.sweep_shift;
+        let freq = if self.sweep_negate {
+            self.shadow_freq.wrapping_sub(delta)
+        } else {
+            self.shadow_freq + delta
+        };
+
+        if freq > 2047 {
+            self.enabled = false;
+        }
+
+        freq
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+
+        if self.sweep_timer > 0 {
+            return;
+        }
+
+        self.sweep_timer = self.sweep_reload();
+
+        if self.sweep_enabled && self.sweep_period > 0 {
+            let freq = self.sweep_freq();
+
+            if freq <= 2047 && self.sweep_shift > 0 {
+                self.shadow_freq = freq;
+                self.freq = freq;
+                self.sweep_freq();
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn update(&mut self) {
+        if self.timer <= 1 {
+            self.timer = (2048 - self.freq) * 4;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+
+    fn save_state(&self, enc: &mut Encoder) {
+        enc.bool(self.enabled);
+        enc.u8(self.duty);
+        enc.u8(self.duty_pos);
+        enc.u16(self.freq);
+        enc.u16(self.timer);
+        self.length.save_state(enc);
+        self.envelope.save_state(enc);
+        enc.u8(self.sweep_period);
+        enc.bool(self.sweep_negate);
+        enc.u8(self.sweep_shift);
+        enc.u8(self.sweep_timer);
+        enc.bool(self.sweep_enabled);
+        enc.u16(self.shadow_freq);
+    }
+
+    fn load_state(&mut self, dec: &mut Decoder) -> io::Result<()> {
+        self.enabled = dec.bool()?;
+        self.duty = dec.u8()?;
+        self.duty_pos = dec.u8()?;
+        self.freq = dec.u16()?;
+        self.timer = dec.u16()?;
+        self.length.load_state(dec)?;
+        self.envelope.load_state(dec)?;
+        self.sweep_period = dec.u8()?;
+        self.sweep_negate = dec.bool()?;
+        self.sweep_shift = dec.u8()?;
+        self.sweep_timer = dec.u8()?;
+        self.sweep_enabled = dec.bool()?;
+        self.shadow_freq = dec.u16()?;
+
+        Ok(())
+    }
+}
+
+/// Wave channel (channel 3).
+struct Wave {
+    /// Whether the channel is playing
+    enabled: bool,
+    /// Whether the DAC is on
+    dac_enabled: bool,
+    /// Output level (0: mute, 1: 100%, 2: 50%, 3: 25%)
+    volume_code: u8,
+    /// Frequency
+    freq: u16,
+    /// Clocks until the next sample
+    timer: u16,
+    /// Position in wave RAM (in 4-bit samples)
+    position: u8,
+    /// Current sample
+    sample: u8,
+    length: LengthCounter,
+    /// Wave pattern RAM
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: LengthCounter::new(256),
+            ram: [0; 0x10],
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // NR30: DAC power
+            0 => {
+                self.dac_enabled = val & 0x80 > 0;
+                self.enabled &= self.dac_enabled;
+            }
+            // NR31: Length
+            1 => self.length.load(val),
+            // NR32: Output level
+            2 => self.volume_code = (val >> 5) & 0x03,
+            // NR33: Frequency low
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            // NR34: Frequency high and control
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val & 0x07) as u16) << 8;
+                self.length.enabled = val & 0x40 > 0;
+
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0x7f | (self.dac_enabled as u8) << 7,
+            1 => 0xff,
+            2 => 0x9f | self.volume_code << 5,
+            3 => 0xff,
+            4 => 0xbf | (self.length.enabled as u8) << 6,
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = (2048 - self.freq) * 2;
+        self.position = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn update(&mut self) {
+        if self.timer <= 1 {
+            self.timer = (2048 - self.freq) * 2;
+            self.position = (self.position + 1) & 0x1f;
+
+            let byte = self.ram[(self.position >> 1) as usize];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0f
+            };
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+
+    fn save_state(&self, enc: &mut Encoder) {
+        enc.bool(self.enabled);
+        enc.bool(self.dac_enabled);
+        enc.u8(self.volume_code);
+        enc.u16(self.freq);
+        enc.u16(self.timer);
+        enc.u8(self.position);
+        enc.u8(self.sample);
+        self.length.save_state(enc);
+        enc.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, dec: &mut Decoder) -> io::Result<()> {
+        self.enabled = dec.bool()?;
+        self.dac_enabled = dec.bool()?;
+        self.volume_code = dec.u8()?;
+        self.freq = dec.u16()?;
+        self.timer = dec.u16()?;
+        self.position = dec.u8()?;
+        self.sample = dec.u8()?;
+        self.length.load_state(dec)?;
+        dec.read_into(&mut self.ram)
+    }
+}
+
+/// Noise channel (channel 4).
+struct Noise {
+    /// Whether the channel is playing
+    enabled: bool,
+    /// Clock shift
+    clock_shift: u8,
+    /// Whether the LFSR is 7 bits wide instead of 15 bits
+    width_mode: bool,
+    /// Clock divisor code
+    divisor_code: u8,
+    /// Clocks until the next LFSR shift
+    timer: u32,
+    /// Linear feedback shift register
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7fff,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // Unused
+            0 => (),
+            // NR41: Length
+            1 => self.length.load(val & 0x3f),
+            // NR42: Envelope
+            2 => {
+                self.envelope.write(val);
+                self.enabled &= self.envelope.dac_enabled();
+            }
+            // NR43: Polynomial counter
+            3 => {
+                self.clock_shift = val >> 4;
+                self.width_mode = val & 0x08 > 0;
+                self.divisor_code = val & 0x07;
+            }
+            // NR44: Control
+            4 => {
+                self.length.enabled = val & 0x40 > 0;
+
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 | 1 => 0xff,
+            2 => self.envelope.read(),
+            3 => self.clock_shift << 4 | (self.width_mode as u8) << 3 | self.divisor_code,
+            4 => 0xbf | (self.length.enabled as u8) << 6,
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7fff;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn update(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | bit << 14;
+
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | bit << 6;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 > 0 {
+            return 0;
+        }
+
+        self.envelope.volume
+    }
+
+    fn save_state(&self, enc: &mut Encoder) {
+        enc.bool(self.enabled);
+        enc.u8(self.read(3));
+        enc.u32(self.timer);
+        enc.u16(self.lfsr);
+        self.length.save_state(enc);
+        self.envelope.save_state(enc);
+    }
+
+    fn load_state(&mut self, dec: &mut Decoder) -> io::Result<()> {
+        self.enabled = dec.bool()?;
+        let nr43 = dec.u8()?;
+        self.write(3, nr43);
+        self.timer = dec.u32()?;
+        self.lfsr = dec.u16()?;
+        self.length.load_state(dec)?;
+        self.envelope.load_state(dec)
+    }
+}
+
+/// Audio Processing Unit.
+pub struct APU {
+    /// Channel 1: Square wave with sweep
+    ch1: Square,
+    /// Channel 2: Square wave
+    ch2: Square,
+    /// Channel 3: Wave
+    ch3: Wave,
+    /// Channel 4: Noise
+    ch4: Noise,
+    /// Master volume and VIN panning
+    nr50: u8,
+    /// Sound panning
+    nr51: u8,
+    /// Sound on/off
+    power: bool,
+    /// Elapsed clocks in current frame sequencer step
+    frame_seq_counter: u16,
+    /// Frame sequencer step
+    frame_seq_step: u8,
+    /// Output sample rate in Hz
+    sample_rate: u32,
+    /// Accumulator for generating samples at the output rate
+    sample_counter: u32,
+    /// Charge factor of the high-pass filter per sample
+    charge_factor: f32,
+    /// High-pass filter capacitors of the left and right outputs
+    capacitor: [f32; 2],
+    /// Generated stereo samples (left and right interleaved)
+    samples: Vec<f32>,
+}
+
+impl APU {
+    /// Creates a new `APU` in the state left by the boot ROM.
+    pub fn new() -> Self {
+        let mut apu = APU {
+            ch1: Square::new(true),
+            ch2: Square::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            nr50: 0,
+            nr51: 0,
+            power: false,
+            frame_seq_counter: 0,
+            frame_seq_step: 0,
+            sample_rate: 0,
+            sample_counter: 0,
+            charge_factor: 0.0,
+            capacitor: [0.0; 2],
+            samples: Vec::new(),
+        };
+
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu.init_post_boot();
+        apu
+    }
+
+    /// Sets the registers to the values left by the boot ROM, which powers
+    /// on the APU and plays its chime on channel 1. The channel is left
+    /// enabled with its envelope faded out.
+    fn init_post_boot(&mut self) {
+        self.power = true;
+        self.nr50 = 0x77;
+        self.nr51 = 0xf3;
+
+        self.ch1.write(0, 0x80);
+        self.ch1.write(1, 0x80);
+        self.ch1.write(2, 0xf3);
+        self.ch1.write(3, 0xc1);
+        self.ch1.write(4, 0x07);
+        self.ch1.enabled = true;
+    }
+
+    /// Sets the output sample rate in Hz.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+        self.charge_factor = 0.999_958_f32.powf(CLOCK_HZ as f32 / sample_rate as f32);
+    }
+
+    /// Returns the output sample rate in Hz.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Takes the stereo samples (left and right interleaved) generated since
+    /// the last call. At most one second of samples is buffered; later
+    /// samples are dropped until the buffer is emptied.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.samples.capacity());
+        ::std::mem::swap(&mut samples, &mut self.samples);
+        samples
+    }
+
+    /// Turns the APU off and clears all registers except wave RAM.
+    fn power_off(&mut self) {
+        let wave_ram = self.ch3.ram;
+
+        self.ch1 = Square::new(true);
+        self.ch2 = Square::new(false);
+        self.ch3 = Wave::new();
+        self.ch3.ram = wave_ram;
+        self.ch4 = Noise::new();
+        self.nr50 = 0;
+        self.nr51 = 0;
+        self.power = false;
+    }
+
+    /// Advances the frame sequencer, which clocks length counters (256 Hz),
+    /// sweep (128 Hz) and envelopes (64 Hz).
+    fn step_frame_sequencer(&mut self) {
+        match self.frame_seq_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => (),
+        }
+
+        self.frame_seq_step = (self.frame_seq_step + 1) & 0x07;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    /// Mixes the channels into a stereo sample.
+    fn mix(&mut self) -> [f32; 2] {
+        let channels = [
+            (self.ch1.output(), self.ch1.envelope.dac_enabled()),
+            (self.ch2.output(), self.ch2.envelope.dac_enabled()),
+            (self.ch3.output(), self.ch3.dac_enabled),
+            (self.ch4.output(), self.ch4.envelope.dac_enabled()),
+        ];
+
+        let mut out = [0.0; 2];
+
+        for (i, &(val, dac_enabled)) in channels.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+
+            // DAC converts 0-15 to -1.0-1.0
+            let analog = val as f32 / 7.5 - 1.0;
+
+            if self.nr51 & (0x10 << i) > 0 {
+                out[0] += analog;
+            }
+            if self.nr51 & (0x01 << i) > 0 {
+                out[1] += analog;
+            }
+        }
+
+        let volume = [(self.nr50 >> 4) & 0x07, self.nr50 & 0x07];
+
+        for (i, sample) in out.iter_mut().enumerate() {
+            *sample *= (volume[i] + 1) as f32 / 32.0;
+
+            // High-pass filter removes the DC offset of the DACs
+            let filtered = *sample - self.capacitor[i];
+            self.capacitor[i] = *sample - filtered * self.charge_factor;
+            *sample = filtered;
+        }
+
+        out
+    }
+
+    /// Serializes the APU state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        self.ch1.save_state(&mut enc);
+        self.ch2.save_state(&mut enc);
+        self.ch3.save_state(&mut enc);
+        self.ch4.save_state(&mut enc);
+        enc.u8(self.nr50);
+        enc.u8(self.nr51);
+        enc.bool(self.power);
+        enc.u16(self.frame_seq_counter);
+        enc.u8(self.frame_seq_step);
+        enc.finish()
+    }
+
+    /// Restores the APU state.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+        self.ch1.load_state(&mut dec)?;
+        self.ch2.load_state(&mut dec)?;
+        self.ch3.load_state(&mut dec)?;
+        self.ch4.load_state(&mut dec)?;
+        self.nr50 = dec.u8()?;
+        self.nr51 = dec.u8()?;
+        self.power = dec.bool()?;
+        self.frame_seq_counter = dec.u16()?;
+        self.frame_seq_step = dec.u8()?;
+
+        Ok(())
+    }
+}
+
+impl IODevice for APU {
+    fn write(&mut self, addr: u16, val: u8) {
+        // Registers are read-only while the APU is off
+        if !self.power && addr < 0xff26 {
+            return;
+        }
+
+        match addr {
+            // Channel 1
+            0xff10..=0xff14 => self.ch1.write(addr - 0xff10, val),
+            // Channel 2
+            0xff15..=0xff19 => self.ch2.write(addr - 0xff15, val),
+            // Channel 3
+            0xff1a..=0xff1e => self.ch3.write(addr - 0xff1a, val),
+            // Channel 4
+            0xff1f..=0xff23 => self.ch4.write(addr - 0xff1f, val),
+            // NR50
+            0xff24 => self.nr50 = val,
+            // NR51
+            0xff25 => self.nr51 = val,
+            // NR52
+            0xff26 => {
+                if val & 0x80 == 0 {
+                    self.power_off();
+                } else if !self.power {
+                    self.power = true;
+                    self.frame_seq_step = 0;
+                }
+            }
+            // Unused
+            0xff27..=0xff2f => (),
+            // Wave RAM
+            0xff30..=0xff3f => self.ch3.ram[(addr - 0xff30) as usize] = val,
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Channel 1
+            0xff10..=0xff14 => self.ch1.read(addr - 0xff10),
+            // Channel 2
+            0xff15..=0xff19 => self.ch2.read(addr - 0xff15),
+            // Channel 3
+            0xff1a..=0xff1e => self.ch3.read(addr - 0xff1a),
+            // Channel 4
+            0xff1f..=0xff23 => self.ch4.read(addr - 0xff1f),
+            // NR50
+            0xff24 => self.nr50,
+            // NR51
+            0xff25 => self.nr51,
+            // NR52
+            0xff26 => {
+                (self.power as u8) << 7
+                    | 0x70
+                    | (self.ch4.enabled as u8) << 3
+                    | (self.ch3.enabled as u8) << 2
+                    | (self.ch2.enabled as u8) << 1
+                    | self.ch1.enabled as u8
+            }
+            // Unused
+            0xff27..=0xff2f => 0xff,
+            // Wave RAM
+            0xff30..=0xff3f => self.ch3.ram[(addr - 0xff30) as usize],
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn update(&mut self, tick: u8) {
+        for _ in 0..tick {
+            if self.power {
+                self.frame_seq_counter += 1;
+                if self.frame_seq_counter >= FRAME_SEQUENCER_PERIOD {
+                    self.frame_seq_counter = 0;
+                    self.step_frame_sequencer();
+                }
+
+                self.ch1.update();
+                self.ch2.update();
+                self.ch3.update();
+                self.ch4.update();
+            }
+
+            self.sample_counter += self.sample_rate;
+            if self.sample_counter >= CLOCK_HZ {
+                self.sample_counter -= CLOCK_HZ;
+
+                let sample = self.mix();
+                if self.samples.len() < 2 * self.sample_rate as usize {
+                    self.samples.extend_from_slice(&sample);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an APU with all channels silenced.
+    fn apu() -> APU {
+        let mut apu = APU::new();
+        apu.write(0xff26, 0x00);
+        apu.write(0xff26, 0x80);
+        apu
+    }
+
+    /// Returns the channel status bits of NR52.
+    fn status(apu: &APU) -> u8 {
+        apu.read(0xff26) & 0x0f
+    }
+
+    #[test]
+    fn post_boot() {
+        let apu = APU::new();
+
+        assert_eq!(apu.read(0xff26), 0xf1);
+        assert_eq!(apu.read(0xff24), 0x77);
+        assert_eq!(apu.read(0xff25), 0xf3);
+        assert_eq!(apu.read(0xff10), 0x80);
+        assert_eq!(apu.read(0xff11), 0xbf);
+        assert_eq!(apu.read(0xff12), 0xf3);
+        assert_eq!(apu.read(0xff14), 0xbf);
+    }
+
+    #[test]
+    fn power_off() {
+        let mut apu = APU::new();
+        apu.write(0xff30, 0x12);
+        apu.write(0xff26, 0x00);
+
+        assert_eq!(apu.read(0xff26), 0x70);
+        assert_eq!(apu.read(0xff24), 0x00);
+        assert_eq!(apu.read(0xff30), 0x12);
+
+        // Registers ignore writes until the APU is powered on again
+        apu.write(0xff24, 0x77);
+        assert_eq!(apu.read(0xff24), 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger() {
+        let mut apu = apu();
+        apu.write(0xff10, 0x11);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff13, 0xff);
+        apu.write(0xff14, 0x87);
+
+        // 0x7ff + (0x7ff >> 1) overflows right away
+        assert_eq!(status(&apu), 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_on_clock() {
+        let mut apu = apu();
+        apu.write(0xff10, 0x11);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x85);
+        assert_eq!(status(&apu), 0x01);
+
+        // 0x500 + 0x280 fits, but the next step 0x780 + 0x3c0 does not
+        apu.ch1.clock_sweep();
+        assert_eq!(apu.ch1.freq, 0x780);
+        assert_eq!(status(&apu), 0x00);
+    }
+
+    #[test]
+    fn sweep_negate() {
+        let mut apu = apu();
+        apu.write(0xff10, 0x19);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x87);
+
+        apu.ch1.clock_sweep();
+        assert_eq!(apu.ch1.freq, 0x700 - 0x380);
+        assert_eq!(status(&apu), 0x01);
+    }
+
+    #[test]
+    fn length_counter() {
+        let mut apu = apu();
+        apu.write(0xff16, 0x3e);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0xc0);
+        assert_eq!(status(&apu), 0x02);
+
+        // Length is clocked on steps 0, 2, 4 and 6
+        apu.step_frame_sequencer();
+        apu.step_frame_sequencer();
+        assert_eq!(status(&apu), 0x02);
+        apu.step_frame_sequencer();
+        assert_eq!(status(&apu), 0x00);
+    }
+
+    #[test]
+    fn length_counter_disabled() {
+        let mut apu = apu();
+        apu.write(0xff16, 0x3f);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+
+        for _ in 0..16 {
+            apu.step_frame_sequencer();
+        }
+        assert_eq!(status(&apu), 0x02);
+    }
+
+    #[test]
+    fn length_reload_on_trigger() {
+        let mut apu = apu();
+        apu.write(0xff1b, 0xff);
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1e, 0xc0);
+
+        apu.step_frame_sequencer();
+        assert_eq!(status(&apu), 0x00);
+
+        // An expired counter is reloaded with the maximum length
+        apu.write(0xff1e, 0xc0);
+        assert_eq!(apu.ch3.length.counter, 256);
+        assert_eq!(status(&apu), 0x04);
+    }
+
+    #[test]
+    fn envelope_decrease() {
+        let mut env = Envelope::new();
+        env.write(0x52);
+        env.trigger();
+        assert_eq!(env.volume, 5);
+
+        env.clock();
+        assert_eq!(env.volume, 5);
+        env.clock();
+        assert_eq!(env.volume, 4);
+
+        for _ in 0..8 {
+            env.clock();
+        }
+        assert_eq!(env.volume, 0);
+        env.clock();
+        env.clock();
+        assert_eq!(env.volume, 0);
+    }
+
+    #[test]
+    fn envelope_increase() {
+        let mut env = Envelope::new();
+        env.write(0xe9);
+        env.trigger();
+
+        env.clock();
+        assert_eq!(env.volume, 15);
+        env.clock();
+        assert_eq!(env.volume, 15);
+    }
+
+    #[test]
+    fn envelope_period_zero() {
+        let mut env = Envelope::new();
+        env.write(0x80);
+        env.trigger();
+
+        for _ in 0..8 {
+            env.clock();
+        }
+        assert_eq!(env.volume, 8);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let mut apu = apu();
+        apu.write(0xff21, 0xf0);
+        apu.write(0xff23, 0x80);
+        assert_eq!(status(&apu), 0x08);
+
+        apu.write(0xff21, 0x00);
+        assert_eq!(status(&apu), 0x00);
+    }
+}
@@ -0,0 +1,634 @@
+//! Runs a ROM without a display and optionally captures a screenshot.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+
+extern crate env_logger;
+extern crate gbr;
+#[macro_use]
+extern crate log;
+
+use gbr::movie::Movie;
+use gbr::palette::Palette;
+use gbr::printer::Printer;
+use gbr::savefile::SaveFormat;
+use gbr::video::VideoRecorder;
+use gbr::{loader, CartridgeHeader, Catridge, GameBoy, Key, SCREEN_H, SCREEN_W};
+
+/// Exit status when the run completed successfully.
+const EXIT_OK: i32 = 0;
+/// Exit status when the stop condition was not met within the frame limit.
+const EXIT_TIMEOUT: i32 = 1;
+/// Exit status for invalid arguments or I/O failures.
+const EXIT_ERROR: i32 = 2;
+/// Exit status when movie playback diverged from the recording.
+const EXIT_DESYNC: i32 = 3;
+/// Exit status when the serial output reported a failure.
+const EXIT_FAIL: i32 = 4;
+
+const USAGE: &str = "Usage: gbr-headless [OPTIONS] ROM
+       gbr-headless info ROM
+       gbr-headless convert-save [--format sav|srm] [--pad N] ROM INPUT OUTPUT
+
+Options:
+    --frames N          Number of frames to run (default: 600)
+    --until ADDR=VAL    Stop once the byte at ADDR equals VAL (hex)
+    --screenshot FILE   Write the last frame to FILE (.png or .ppm)
+    --palette NAME      Colour palette for screenshots and video (grey, dmg,
+                        pocket, light or one from the palette config)
+    --palettes FILE     Read user palettes from FILE
+    --video PATH        Record every frame to an animated GIF (PATH.gif),
+                        raw RGB24 on stdout (-) or PNGs in a directory
+    --input F:KEY:ACT   Press (down) or release (up) KEY at frame F
+    --script FILE       Read inputs from FILE, one `F KEY ACT` per line
+    --force             Load the ROM even if its header is invalid
+    --entry NAME        Load NAME from a zip archive instead of the first ROM
+    --patch FILE        Apply an IPS, UPS or BPS patch (default: same-named
+                        patch file next to the ROM, if any)
+    --load-state FILE   Restore a save state before running
+    --save-state FILE   Write a save state after running
+    --record FILE       Record inputs to a movie file
+    --play FILE         Replay a movie file instead of running --frames
+    --hash-interval N   Store a frame hash in recorded movies every N frames
+                        to detect desyncs during playback (default: 0, off)
+    --serial            Print bytes sent through the serial port to stdout
+    --serial-pass TEXT  Stop successfully once TEXT is sent through the serial
+                        port
+    --serial-fail TEXT  Stop with exit status 4 once TEXT is sent through the
+                        serial port
+    --printer DIR       Connect a Game Boy Printer that saves printouts as
+                        PNGs in DIR";
+
+/// A scripted joypad input.
+struct Input {
+    frame: u32,
+    key: Key,
+    down: bool,
+}
+
+/// Command line options.
+struct Options {
+    rom: String,
+    frames: u32,
+    until: Option<(u16, u8)>,
+    screenshot: Option<String>,
+    video: Option<String>,
+    palette: Option<String>,
+    palettes: Option<String>,
+    inputs: Vec<Input>,
+    force: bool,
+    entry: Option<String>,
+    patch: Option<String>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    record: Option<String>,
+    play: Option<String>,
+    hash_interval: u32,
+    printer: Option<String>,
+    serial: bool,
+    serial_pass: Option<String>,
+    serial_fail: Option<String>,
+}
+
+/// Parses a key name.
+fn parse_key(name: &str) -> Result<Key, String> {
+    match name.to_lowercase().as_str() {
+        "down" => Ok(Key::Down),
+        "up" => Ok(Key::Up),
+        "left" => Ok(Key::Left),
+        "right" => Ok(Key::Right),
+        "start" => Ok(Key::Start),
+        "select" => Ok(Key::Select),
+        "b" => Ok(Key::B),
+        "a" => Ok(Key::A),
+        _ => Err(format!("Unknown key: {}", name)),
+    }
+}
+
+/// Parses an input of the form `FRAME KEY ACTION`, separated by colons or
+/// whitespace.
+fn parse_input(s: &str) -> Result<Input, String> {
+    let fields: Vec<&str> = s
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .filter(|f| !f.is_empty())
+        .collect();
+
+    if fields.len() != 3 {
+        return Err(format!("Invalid input: {}", s));
+    }
+
+    let frame = fields[0]
+        .parse()
+        .map_err(|_| format!("Invalid frame number: {}", fields[0]))?;
+    let key = parse_key(fields[1])?;
+    let down = match fields[2] {
+        "down" => true,
+        "up" => false,
+        _ => return Err(format!("Invalid key action: {}", fields[2])),
+    };
+
+    Ok(Input { frame, key, down })
+}
+
+/// Reads inputs from a script file. Empty lines and lines starting with `#`
+/// are ignored.
+fn read_script(fname: &str) -> Result<Vec<Input>, String> {
+    let file = File::open(fname).map_err(|e| format!("{}: {}", fname, e))?;
+    let mut inputs = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", fname, e))?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        inputs.push(parse_input(line)?);
+    }
+
+    Ok(inputs)
+}
+
+/// Parses a condition of the form `ADDR=VAL`.
+fn parse_until(s: &str) -> Result<(u16, u8), String> {
+    let err = || format!("Invalid condition: {}", s);
+    let mut it = s.splitn(2, '=');
+    let addr = it.next().ok_or_else(err)?.trim_start_matches("0x");
+    let val = it.next().ok_or_else(err)?.trim_start_matches("0x");
+
+    let addr = u16::from_str_radix(addr, 16).map_err(|_| err())?;
+    let val = u8::from_str_radix(val, 16).map_err(|_| err())?;
+
+    Ok((addr, val))
+}
+
+/// Parses command line arguments.
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut rom = None;
+    let mut frames = 600;
+    let mut until = None;
+    let mut screenshot = None;
+    let mut video = None;
+    let mut palette = None;
+    let mut palettes = None;
+    let mut inputs = Vec::new();
+    let mut force = false;
+    let mut entry = None;
+    let mut patch = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut record = None;
+    let mut play = None;
+    let mut hash_interval = 0;
+    let mut printer = None;
+    let mut serial = false;
+    let mut serial_pass = None;
+    let mut serial_fail = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+
+        match arg.as_str() {
+            "--frames" => {
+                let n = value()?;
+                frames = n.parse().map_err(|_| format!("Invalid frames: {}", n))?;
+            }
+            "--until" => until = Some(parse_until(&value()?)?),
+            "--screenshot" => screenshot = Some(value()?),
+            "--video" => video = Some(value()?),
+            "--palette" => palette = Some(value()?),
+            "--palettes" => palettes = Some(value()?),
+            "--input" => inputs.push(parse_input(&value()?)?),
+            "--script" => inputs.extend(read_script(&value()?)?),
+            "--force" => force = true,
+            "--entry" => entry = Some(value()?),
+            "--patch" => patch = Some(value()?),
+            "--load-state" => load_state = Some(value()?),
+            "--save-state" => save_state = Some(value()?),
+            "--record" => record = Some(value()?),
+            "--play" => play = Some(value()?),
+            "--hash-interval" => {
+                let n = value()?;
+                hash_interval = n
+                    .parse()
+                    .map_err(|_| format!("Invalid hash interval: {}", n))?;
+            }
+            "--printer" => printer = Some(value()?),
+            "--serial" => serial = true,
+            "--serial-pass" => serial_pass = Some(value()?),
+            "--serial-fail" => serial_fail = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    inputs.sort_by_key(|input| input.frame);
+
+    if record.is_some() && play.is_some() {
+        return Err(String::from("--record and --play are exclusive"));
+    }
+
+    if serial_pass.as_deref() == Some("") || serial_fail.as_deref() == Some("") {
+        return Err(String::from(
+            "--serial-pass and --serial-fail need a non-empty text",
+        ));
+    }
+
+    if serial && video.as_deref() == Some("-") {
+        return Err(String::from("--serial and --video - both write to stdout"));
+    }
+
+    Ok(Options {
+        rom: rom.ok_or("ROM not specified")?,
+        frames,
+        until,
+        screenshot,
+        video,
+        palette,
+        palettes,
+        inputs,
+        force,
+        entry,
+        patch,
+        load_state,
+        save_state,
+        record,
+        play,
+        hash_interval,
+        printer,
+        serial,
+        serial_pass,
+        serial_fail,
+    })
+}
+
+/// Returns whether `text` appears in `buf`.
+fn contains(buf: &[u8], text: &str) -> bool {
+    buf.windows(text.len())
+        .any(|window| window == text.as_bytes())
+}
+
+/// Converts a save file from another emulator or flash cart for a given ROM.
+fn convert_save(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let mut format = None;
+    let mut pad = 0;
+    let mut fnames = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+
+        match arg.as_str() {
+            "--format" => {
+                format = match value()?.as_str() {
+                    "sav" => Some(SaveFormat::Sav),
+                    "srm" => Some(SaveFormat::Srm),
+                    f => return Err(format!("Unknown save format: {}", f)),
+                }
+            }
+            "--pad" => {
+                let n = value()?;
+                pad = n.parse().map_err(|_| format!("Invalid size: {}", n))?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => fnames.push(arg.as_str()),
+        }
+    }
+
+    let (rom, input, output) = match fnames[..] {
+        [rom, input, output] => (rom, input, output),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let mut catridge = Catridge::new(rom, true).map_err(|e| format!("{}: {}", rom, e))?;
+    let buf = fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+
+    let layout = catridge.import_save(&buf);
+    println!("{}: {} ({} bytes)", input, layout, buf.len());
+
+    let format = format.unwrap_or_else(|| SaveFormat::from_fname(output));
+    let mut buf = catridge.export_save(format);
+    if buf.len() < pad {
+        buf.resize(pad, 0xff);
+    }
+
+    fs::write(output, &buf).map_err(|e| format!("{}: {}", output, e))?;
+    println!("{}: {} ({} bytes)", output, format, buf.len());
+
+    Ok(())
+}
+
+/// Returns "OK" or "BAD" depending on a check result.
+fn ok(valid: bool) -> &'static str {
+    if valid {
+        "OK"
+    } else {
+        "BAD"
+    }
+}
+
+/// Prints the header of a ROM.
+fn print_info(fname: &str) -> Result<(), String> {
+    let rom = loader::read_rom(fname, None).map_err(|e| format!("{}: {}", fname, e))?;
+    let header = CartridgeHeader::parse(&rom).map_err(|e| format!("{}: {}", fname, e))?;
+    let header_chksum = CartridgeHeader::compute_header_checksum(&rom);
+    let global_chksum = CartridgeHeader::compute_global_checksum(&rom);
+
+    let cgb = if header.cgb_only() {
+        "CGB only"
+    } else if header.supports_cgb() {
+        "CGB enhanced"
+    } else {
+        "DMG"
+    };
+    let ram_size = match header.ram_size() {
+        Some(size) => format!("{}KB", size / 1024),
+        None => String::from("invalid"),
+    };
+    let rom_size = match header.rom_size() {
+        Some(size) => format!("{}KB", size / 1024),
+        None => String::from("unknown"),
+    };
+    let destination = if header.is_japanese() {
+        "Japanese"
+    } else {
+        "Non-Japanese"
+    };
+
+    println!("Title:            {}", header.title);
+    println!(
+        "Manufacturer:     {}",
+        header
+            .manufacturer_code
+            .as_ref()
+            .map_or("-", |s| s.as_str())
+    );
+    println!("CGB flag:         0x{:02x} ({})", header.cgb_flag, cgb);
+    println!(
+        "SGB flag:         0x{:02x} ({})",
+        header.sgb_flag,
+        if header.supports_sgb() {
+            "SGB"
+        } else {
+            "no SGB"
+        }
+    );
+    println!(
+        "Licensee:         {} (old 0x{:02x}, new \"{}\")",
+        header.licensee_code(),
+        header.old_licensee_code,
+        header.new_licensee_code
+    );
+    println!(
+        "Catridge type:    0x{:02x} ({})",
+        header.mbc_type,
+        header.mbc_name()
+    );
+    println!("ROM size:         {}", rom_size);
+    println!("RAM size:         {}", ram_size);
+    println!(
+        "Destination:      0x{:02x} ({})",
+        header.destination, destination
+    );
+    println!("Version:          {}", header.version);
+    println!(
+        "Header checksum:  0x{:02x} ({})",
+        header.header_checksum,
+        ok(header.header_checksum == header_chksum)
+    );
+    println!(
+        "Global checksum:  0x{:04x} ({})",
+        header.global_checksum,
+        ok(header.global_checksum == global_chksum)
+    );
+    println!("Nintendo logo:    {}", ok(header.logo_valid));
+
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "info" {
+        if let Err(msg) = print_info(&args[2]) {
+            eprintln!("{}", msg);
+            process::exit(EXIT_ERROR);
+        }
+        return;
+    }
+
+    if args.len() > 1 && args[1] == "convert-save" {
+        if let Err(msg) = convert_save(&args[2..]) {
+            eprintln!("{}", msg);
+            process::exit(EXIT_ERROR);
+        }
+        return;
+    }
+
+    let opts = match parse_args() {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let patch = opts.patch.clone().or_else(|| loader::find_patch(&opts.rom));
+    let catridge = loader::read_rom(&opts.rom, opts.entry.as_deref())
+        .and_then(|rom| match patch {
+            Some(ref patch) => loader::apply_patch_file(&rom, patch),
+            None => Ok(rom),
+        })
+        .and_then(|rom| Catridge::from_rom(rom, opts.force));
+
+    let catridge = match catridge {
+        Ok(catridge) => catridge,
+        Err(e) => {
+            eprintln!("{}: {}", opts.rom, e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let mut gameboy = GameBoy::new(catridge);
+
+    if let Some(ref fname) = opts.load_state {
+        if let Err(e) = gameboy.load_state_file(fname) {
+            eprintln!("{}: {}", fname, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    if let Some(ref dir) = opts.printer {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("{}: {}", dir, e);
+            process::exit(EXIT_ERROR);
+        }
+        gameboy.set_serial_device(Some(Box::new(Printer::new(dir.as_str()))));
+    }
+
+    let playback = opts.play.as_ref().map(|fname| {
+        match Movie::load(fname).and_then(|movie| movie.rewind(&mut gameboy).map(|_| movie)) {
+            Ok(movie) => movie,
+            Err(e) => {
+                eprintln!("{}: {}", fname, e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    });
+    let mut recording = opts
+        .record
+        .as_ref()
+        .map(|_| Movie::new(&gameboy, opts.hash_interval));
+    let frames = match playback {
+        Some(ref movie) => movie.len() as u32,
+        None => opts.frames,
+    };
+
+    if let Some(ref name) = opts.palette {
+        let palettes = match Palette::load_all(opts.palettes.as_deref()) {
+            Ok(palettes) => palettes,
+            Err(e) => {
+                eprintln!(
+                    "{}: {}",
+                    opts.palettes.as_deref().unwrap_or("palette config"),
+                    e
+                );
+                process::exit(EXIT_ERROR);
+            }
+        };
+
+        match palettes.into_iter().find(|palette| &palette.name == name) {
+            Some(palette) => gameboy.set_palette(palette),
+            None => {
+                eprintln!("Unknown palette: {}", name);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    let mut video = opts
+        .video
+        .as_ref()
+        .map(|path| match VideoRecorder::create(path) {
+            Ok(video) => video,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(EXIT_ERROR);
+            }
+        });
+
+    let mut inputs = opts.inputs.into_iter().peekable();
+    let mut serial_log = Vec::new();
+    let mut status = if opts.until.is_some() || opts.serial_pass.is_some() {
+        EXIT_TIMEOUT
+    } else {
+        EXIT_OK
+    };
+
+    for frame in 0..frames {
+        while let Some(input) = inputs.peek() {
+            if input.frame > frame {
+                break;
+            }
+
+            if input.down {
+                gameboy.keydown(input.key);
+            } else {
+                gameboy.keyup(input.key);
+            }
+
+            inputs.next();
+        }
+
+        if let Some(ref movie) = playback {
+            if let Err(e) = movie.play_frame(frame as usize, &mut gameboy) {
+                eprintln!("{}", e);
+                process::exit(EXIT_DESYNC);
+            }
+        } else if let Some(ref mut movie) = recording {
+            movie.record_frame(&mut gameboy);
+        } else {
+            gameboy.run_frame();
+        }
+
+        if let Some(ref mut video) = video {
+            if let Err(e) = video.add_frame(&gameboy.frame_rgb()) {
+                eprintln!("{}: {}", opts.video.as_ref().unwrap(), e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+
+        if let Some((addr, val)) = opts.until {
+            if gameboy.peek(addr) == val {
+                info!("Condition met at frame {}", frame);
+                status = EXIT_OK;
+                break;
+            }
+        }
+
+        let output = gameboy.take_serial_output();
+        if !output.is_empty() {
+            if opts.serial {
+                let mut stdout = io::stdout();
+                if let Err(e) = stdout.write_all(&output).and_then(|_| stdout.flush()) {
+                    eprintln!("stdout: {}", e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            serial_log.extend(output);
+
+            if let Some(ref text) = opts.serial_fail {
+                if contains(&serial_log, text) {
+                    info!("Failure reported at frame {}", frame);
+                    status = EXIT_FAIL;
+                    break;
+                }
+            }
+
+            if let Some(ref text) = opts.serial_pass {
+                if contains(&serial_log, text) {
+                    info!("Success reported at frame {}", frame);
+                    status = EXIT_OK;
+                    break;
+                }
+            }
+        }
+    }
+
+    if let (Some(path), Some(video)) = (opts.video, video) {
+        if let Err(e) = video.finish() {
+            eprintln!("{}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    if let (Some(fname), Some(movie)) = (opts.record, recording) {
+        if let Err(e) = movie.save(&fname) {
+            eprintln!("{}: {}", fname, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    if let Some(fname) = opts.screenshot {
+        let (w, h) = (SCREEN_W as u32, SCREEN_H as u32);
+
+        if let Err(e) = gbr::image::write_image(&fname, w, h, &gameboy.frame_rgb()) {
+            eprintln!("{}: {}", fname, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    if let Some(fname) = opts.save_state {
+        if let Err(e) = gameboy.save_state_file(&fname) {
+            eprintln!("{}: {}", fname, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    process::exit(status);
+}
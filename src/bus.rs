@@ -0,0 +1,32 @@
+/// Memory space and devices as seen by the CPU.
+///
+/// The CPU does not tick the bus on every memory access. It calls
+/// `tick_cycles` once after each instruction (and once after dispatching an
+/// interrupt) with the number of M-cycles it took, so devices observe the
+/// memory accesses of an instruction before its cycles have elapsed.
+pub trait Bus {
+    /// Reads a byte from an address.
+    fn read(&mut self, addr: u16) -> u8;
+
+    /// Writes a byte to an address.
+    fn write(&mut self, addr: u16, val: u8);
+
+    /// Advances the devices by one M-cycle (4 clocks).
+    fn tick(&mut self);
+
+    /// Advances the devices by a number of M-cycles. This is what the CPU
+    /// calls; the default implementation calls `tick` for each cycle, and
+    /// buses that can advance their devices in one go may override it.
+    fn tick_cycles(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    /// Returns the interrupts that are both requested and enabled, one bit
+    /// per interrupt as in the IF register.
+    fn pending_interrupts(&self) -> u8;
+
+    /// Clears the request of an interrupt whose handler is being called.
+    fn ack_interrupt(&mut self, id: u8);
+}
@@ -1,165 +1,341 @@
-use std::fs::File;
-use std::io::{Read, Write};
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
 
+use header::CartridgeHeader;
 use io_device::IODevice;
+use loader;
+use mapper::{self, Mapper};
+use savefile::{self, SaveFormat, SaveLayout};
+use state::{Decoder, Encoder};
+
+/// Error returned when a catridge cannot be loaded.
+#[derive(Debug)]
+pub enum CartridgeError {
+    /// The ROM file could not be read
+    Io(io::Error),
+    /// The ROM is shorter than the header or the size declared in the header
+    TruncatedRom { expected: usize, actual: usize },
+    /// The header declares an invalid ROM size
+    InvalidRomSize(u8),
+    /// The header declares an invalid RAM size
+    InvalidRamSize(u8),
+    /// The catridge type is not supported
+    UnknownMapper(u8),
+    /// The header checksum (0x014d) does not match
+    BadHeaderChecksum { expected: u8, actual: u8 },
+    /// The ROM could not be extracted from an archive
+    Archive(String),
+    /// A patch could not be applied
+    Patch(String),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CartridgeError::Io(ref e) => write!(f, "Failed to read ROM: {}", e),
+            CartridgeError::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM is truncated ({} bytes, expected {})",
+                actual, expected
+            ),
+            CartridgeError::InvalidRomSize(n) => write!(f, "ROM size 0x{:02x} is invalid", n),
+            CartridgeError::InvalidRamSize(n) => write!(f, "RAM size 0x{:02x} is invalid", n),
+            CartridgeError::UnknownMapper(n) => {
+                write!(f, "Catridge type 0x{:02x} is not supported", n)
+            }
+            CartridgeError::BadHeaderChecksum { expected, actual } => write!(
+                f,
+                "ROM header checksum is incorrect (0x{:02x}, expected 0x{:02x})",
+                actual, expected
+            ),
+            CartridgeError::Archive(ref msg) => write!(f, "Failed to extract ROM: {}", msg),
+            CartridgeError::Patch(ref msg) => write!(f, "Failed to apply patch: {}", msg),
+        }
+    }
+}
+
+impl error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CartridgeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
 
 pub struct Catridge {
+    /// Catridge header
+    header: CartridgeHeader,
     rom: Vec<u8>,
     ram: Vec<u8>,
-    #[allow(dead_code)]
-    mbc_type: u8,
-    ram_enable: bool,
-    bank_no_upper: u8,
-    bank_no_lower: u8,
-    num_rom_banks: u8,
-    mode: bool,
+    /// Memory bank controller
+    mapper: Box<dyn Mapper>,
+    /// Whether external RAM has been written since the last save
+    dirty: bool,
+}
+
+/// Fails with a given error unless `force` is set, in which case the error is
+/// only logged as a warning.
+fn check(err: CartridgeError, force: bool) -> Result<(), CartridgeError> {
+    if force {
+        warn!("{}, loading anyway", err);
+        Ok(())
+    } else {
+        Err(err)
+    }
 }
 
 impl Catridge {
-    pub fn new(fname: &str) -> Self {
-        let mut rom = Vec::new();
-        let mut file = File::open(fname).unwrap();
-        file.read_to_end(&mut rom).unwrap();
-
-        let rom_size: usize = match rom[0x0148] {
-            0 => 32 * 1024,
-            n => 32 * 1024 << (n as usize),
-        };
+    /// Loads a catridge from a ROM file, which may be compressed with gzip or
+    /// zip. An IPS, UPS or BPS patch with the same name as the ROM file is
+    /// applied if present.
+    ///
+    /// If `force` is set, size and header checksum mismatches and unsupported
+    /// catridge types are logged as warnings instead of failing.
+    pub fn new(fname: &str, force: bool) -> Result<Self, CartridgeError> {
+        let mut rom = loader::read_rom(fname, None)?;
+
+        if let Some(patch_fname) = loader::find_patch(fname) {
+            rom = loader::apply_patch_file(&rom, &patch_fname)?;
+        }
 
-        let num_rom_banks = 2 << rom[0x0148];
+        Self::from_rom(rom, force)
+    }
+
+    /// Loads a catridge from a ROM image in memory.
+    pub fn from_rom(mut rom: Vec<u8>, force: bool) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let rom_size = match header.rom_size() {
+            Some(rom_size) => rom_size,
+            None => return Err(CartridgeError::InvalidRomSize(header.rom_size_code)),
+        };
+        let mbc_type = header.mbc_type;
 
-        let ram_size: usize = match rom[0x0149] {
-            0 => 0,
-            1 => 2 * 1024,
-            2 => 8 * 1024,
-            3 => 32 * 1024,
-            4 => 128 * 1024,
-            5 => 64 * 1024,
-            _ => panic!("RAM size invalid"),
+        let ram_size = match header.ram_size() {
+            Some(ram_size) => ram_size,
+            None => return Err(CartridgeError::InvalidRamSize(header.ram_size_code)),
         };
 
-        let mbc_type = rom[0x0147];
-
-        let mbc_name = match mbc_type {
-            0x00 => "ROM ONLY",
-            0x01 => "MBC1",
-            0x02 => "MBC1+RAM",
-            0x03 => "MBC1+RAM+BATTERY",
-            0x05 => "MBC2",
-            0x06 => "MBC2+BATTERY",
-            0x08 => "ROM+RAM",
-            0x09 => "ROM+RAM+BATTERY",
-            0x0b => "MMM01",
-            0x0c => "MMM01+RAM",
-            0x0d => "MMM01+RAM+BATTERY",
-            0x0f => "MBC3+TIMER+BATTERY",
-            0x10 => "MBC3+TIMER+RAM+BATTERY",
-            0x11 => "MBC3",
-            0x12 => "MBC3+RAM",
-            0x13 => "MBC3+RAM+BATTERY",
-            0x19 => "MBC5",
-            0x1a => "MBC5+RAM",
-            0x1b => "MBC5+RAM+BATTERY",
-            0x1c => "MBC5+RUMBLE",
-            0x1d => "MBC5+RUMBLE+RAM",
-            0x1e => "MBC5+RUMBLE+RAM+BATTERY",
-            0x20 => "MBC6",
-            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
-            0xfc => "POCKET CAMERA",
-            0xfd => "BANDAI TAMA5",
-            0xfe => "HuC3",
-            0xff => "HuC1+RAM+BATTERY",
-            _ => "Unknown",
+        // MBC2 has 512x4 bits of built-in RAM regardless of the header
+        let ram_size = match mbc_type {
+            0x05 | 0x06 => 512,
+            _ => ram_size,
         };
 
-        let mut chksum: u8 = 0;
-        for i in 0x0134..0x014d {
-            chksum = chksum.wrapping_sub(rom[i]).wrapping_sub(1);
+        let chksum = CartridgeHeader::compute_header_checksum(&rom);
+        if chksum != header.header_checksum {
+            check(
+                CartridgeError::BadHeaderChecksum {
+                    expected: header.header_checksum,
+                    actual: chksum,
+                },
+                force,
+            )?;
         }
 
-        if rom_size != rom.len() {
-            panic!("ROM file invalid");
+        // The global checksum is not verified by the hardware
+        let global_chksum = CartridgeHeader::compute_global_checksum(&rom);
+        if global_chksum != header.global_checksum {
+            warn!(
+                "ROM global checksum is incorrect (0x{:04x}, expected 0x{:04x})",
+                global_chksum, header.global_checksum
+            );
         }
 
-        if chksum != rom[0x014d] {
-            panic!("ROM header checksum is incorrect");
+        if rom.len() < rom_size {
+            check(
+                CartridgeError::TruncatedRom {
+                    expected: rom_size,
+                    actual: rom.len(),
+                },
+                force,
+            )?;
+            rom.resize(rom_size, 0xff);
+        } else if rom.len() > rom_size {
+            warn!(
+                "ROM is larger than declared in header ({} bytes, expected {})",
+                rom.len(),
+                rom_size
+            );
         }
 
+        let mapper = match mapper::new_mapper(mbc_type) {
+            Some(mapper) => mapper,
+            None => {
+                check(CartridgeError::UnknownMapper(mbc_type), force)?;
+                Box::new(mapper::Mbc1::new())
+            }
+        };
+
+        info!("Title {}", header.title);
         info!("ROM size {}KB", rom_size / 1024);
-        info!("RAM size {}KB", ram_size / 1024);
-        info!("MBC type {}", mbc_name);
+        info!("RAM size {}B", ram_size);
+        info!("MBC type {}", header.mbc_name());
 
-        Catridge {
-            rom: rom,
+        Ok(Catridge {
+            header,
+            rom,
             ram: vec![0; ram_size],
-            mbc_type: mbc_type,
-            ram_enable: false,
-            bank_no_upper: 0,
-            bank_no_lower: 0,
-            num_rom_banks: num_rom_banks,
-            mode: false,
-        }
+            mapper,
+            dirty: false,
+        })
     }
 
-    fn rom_bank_no(&self) -> u8 {
-        let bank_no = if self.mode {
-            self.bank_no_lower
-        } else {
-            self.bank_no_upper << 5 | self.bank_no_lower
-        };
+    /// Returns the catridge header.
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
 
-        let bank_no = match bank_no {
-            0 | 0x20 | 0x40 | 0x60 => bank_no + 1,
-            _ => bank_no,
-        };
+    /// Returns whether the rumble motor is currently on.
+    pub fn rumble(&self) -> bool {
+        self.mapper.rumble()
+    }
 
-        bank_no & (self.num_rom_banks - 1)
+    /// Serializes the mapper state and external RAM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.u8(self.header.header_checksum);
+        enc.u16(self.header.global_checksum);
+        enc.blob(&self.mapper.save_state());
+        enc.blob(&self.ram);
+        enc.finish()
     }
 
-    fn ram_bank_no(&self) -> u8 {
-        if self.mode {
-            self.bank_no_upper
-        } else {
-            0
+    /// Restores the mapper state and external RAM.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+
+        if dec.u8()? != self.header.header_checksum || dec.u16()? != self.header.global_checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Save state was created with a different ROM",
+            ));
         }
+
+        let mapper_state = dec.blob()?;
+        let ram = dec.blob()?;
+        if ram.len() != self.ram.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Save state has an incompatible RAM size",
+            ));
+        }
+
+        self.mapper.load_state(mapper_state)?;
+        self.ram.copy_from_slice(ram);
+
+        Ok(())
+    }
+
+    /// Returns whether the catridge has a battery, i.e. whether its RAM
+    /// should be persisted to a save file.
+    pub fn has_battery(&self) -> bool {
+        self.header.has_battery()
+    }
+
+    /// Returns whether external RAM has been written since it was last saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
     }
 
-    pub fn read_save_file(&mut self, fname: &str) {
+    /// Loads external RAM (and mapper state such as the RTC) from a save
+    /// file. A missing save file is not an error.
+    pub fn read_save_file(&mut self, fname: &str) -> io::Result<()> {
+        let buf = match fs::read(fname) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
         info!("Reading save file from: {}", fname);
 
-        if let Ok(mut file) = File::open(fname) {
-            self.ram = Vec::new();
-            file.read_to_end(&mut self.ram).unwrap();
+        let layout = savefile::detect(&buf, self.ram.len(), self.mapper.footer_sizes());
+        if !layout.is_native() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Save file has {} bytes, expected {} bytes of RAM ({}); \
+                     convert it with `gbr-headless convert-save`",
+                    buf.len(),
+                    self.ram.len(),
+                    layout
+                ),
+            ));
         }
+
+        self.import_save(&buf);
+        self.dirty = false;
+
+        Ok(())
     }
 
-    pub fn write_save_file(&mut self, fname: &str) {
-        info!("Writing save file to: {}", fname);
+    /// Loads external RAM (and mapper state such as the RTC) from the
+    /// contents of a save file in any supported layout and returns the
+    /// detected layout.
+    pub fn import_save(&mut self, buf: &[u8]) -> SaveLayout {
+        let layout = savefile::detect(buf, self.ram.len(), self.mapper.footer_sizes());
+        let (ram, footer) = savefile::split(buf, self.ram.len(), layout);
+
+        self.ram = ram;
+        self.mapper.load_footer(footer);
+        self.dirty = true;
+
+        layout
+    }
 
-        if let Ok(mut file) = File::create(fname) {
-            file.write_all(&mut self.ram).unwrap();
+    /// Returns the contents of a save file in a given format.
+    pub fn export_save(&self, format: SaveFormat) -> Vec<u8> {
+        let mut buf = self.ram.clone();
+
+        if format == SaveFormat::Sav {
+            // Mapper state (e.g. RTC) is appended after the RAM contents
+            buf.extend(self.mapper.save_footer());
         }
+
+        buf
+    }
+
+    /// Writes external RAM (and mapper state such as the RTC) to a save file
+    /// if the catridge has a battery. The file is written to a temporary
+    /// file first and then renamed, so an interrupted write never leaves a
+    /// corrupted save file behind.
+    pub fn write_save_file(&mut self, fname: &str) -> io::Result<()> {
+        if !self.has_battery() {
+            return Ok(());
+        }
+
+        info!("Writing save file to: {}", fname);
+
+        let tmp_fname = format!("{}.tmp", fname);
+        let mut file = File::create(&tmp_fname)?;
+        file.write_all(&self.export_save(SaveFormat::Sav))?;
+        file.sync_all()?;
+        fs::rename(&tmp_fname, fname)?;
+
+        self.dirty = false;
+
+        Ok(())
     }
 }
 
 impl IODevice for Catridge {
     fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            // RAM enable
-            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
-            // ROM bank number (lower 5 bits)
-            0x2000..=0x3fff => self.bank_no_lower = val & 0x1f,
-            // RAM bank number or ROM bank number (upper 2 bits)
-            0x4000..=0x5fff => self.bank_no_upper = val & 0x03,
-            // ROM/RAM mode select
-            0x6000..=0x7fff => self.mode = val & 0x01 > 0,
-            // RAM bank 00-03
+            // Mapper registers
+            0x0000..=0x7fff => self.mapper.write_register(addr, val),
+            // External RAM
             0xa000..=0xbfff => {
-                if !self.ram_enable {
-                    return;
+                if self.mapper.write_ram(&mut self.ram, addr, val) {
+                    self.dirty = true;
                 }
-                let offset = (8 * 1024) * self.ram_bank_no() as usize;
-                self.ram[(addr & 0x1fff) as usize + offset] = val
             }
             _ => unreachable!("Unexpected address: 0x{:04x}", addr),
         }
@@ -167,24 +343,15 @@ impl IODevice for Catridge {
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            // ROM bank 00
-            0x0000..=0x3fff => self.rom[addr as usize],
-            // ROM bank 01-7f
-            0x4000..=0x7fff => {
-                let offset = (16 * 1024) * self.rom_bank_no() as usize;
-                self.rom[(addr & 0x3fff) as usize + offset]
-            }
-            // RAM bank 00-03
-            0xa000..=0xbfff => {
-                if !self.ram_enable {
-                    return 0xff;
-                }
-                let offset = (8 * 1024) * self.ram_bank_no() as usize;
-                self.ram[(addr & 0x1fff) as usize + offset]
-            }
+            // ROM
+            0x0000..=0x7fff => self.mapper.read_rom(&self.rom, addr),
+            // External RAM
+            0xa000..=0xbfff => self.mapper.read_ram(&self.ram, addr),
             _ => unreachable!("Unexpected address: 0x{:04x}", addr),
         }
     }
 
-    fn update(&mut self, _tick: u8) {}
+    fn update(&mut self, tick: u8) {
+        self.mapper.tick(tick);
+    }
 }
@@ -1,7 +1,28 @@
+use std::io;
+
+use bus::Bus;
+use catridge::Catridge;
 use mmu::MMU;
+use state::{Decoder, Encoder, StateReader, StateWriter};
+
+/// Values of the CPU registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
 
-pub struct CPU {
-    pub mmu: MMU,
+/// CPU attached to a memory space and devices through a `Bus`.
+pub struct CPU<B: Bus = MMU> {
+    pub bus: B,
     pc: u16,
     sp: u16,
     a: u8,
@@ -17,11 +38,53 @@ pub struct CPU {
     halted: bool,
 }
 
-impl CPU {
-    /// Creates a new `CPU`
-    pub fn new(rom_name: &str) -> Self {
+impl CPU<MMU> {
+    /// Creates a new `CPU` with a given catridge inserted.
+    pub fn new(catridge: Catridge) -> Self {
+        Self::with_bus(MMU::new(catridge))
+    }
+
+    /// Writes the state of the CPU and memory to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        let mut enc = Encoder::new();
+        enc.u16(self.pc);
+        enc.u16(self.sp);
+        enc.bytes(&[
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ]);
+        enc.bool(self.ime);
+        enc.bool(self.halted);
+
+        writer.chunk(b"CPU ", &enc.finish());
+        self.bus.save_state(writer);
+    }
+
+    /// Restores the state of the CPU and memory from a save state.
+    pub fn load_state(&mut self, reader: &StateReader) -> io::Result<()> {
+        let mut dec = Decoder::new(reader.chunk(b"CPU ")?);
+        self.pc = dec.u16()?;
+        self.sp = dec.u16()?;
+        let regs = dec.bytes(8)?;
+        self.a = regs[0];
+        self.f = regs[1];
+        self.b = regs[2];
+        self.c = regs[3];
+        self.d = regs[4];
+        self.e = regs[5];
+        self.h = regs[6];
+        self.l = regs[7];
+        self.ime = dec.bool()?;
+        self.halted = dec.bool()?;
+
+        self.bus.load_state(reader)
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Creates a new `CPU` attached to a given bus.
+    pub fn with_bus(bus: B) -> Self {
         CPU {
-            mmu: MMU::new(rom_name),
+            bus,
             pc: 0x100,
             sp: 0,
             a: 0,
@@ -38,6 +101,46 @@ impl CPU {
         }
     }
 
+    /// Returns the current values of the registers.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            f: self.f,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    /// Sets the values of the registers.
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.a = regs.a;
+        self.f = regs.f;
+        self.b = regs.b;
+        self.c = regs.c;
+        self.d = regs.d;
+        self.e = regs.e;
+        self.h = regs.h;
+        self.l = regs.l;
+        self.sp = regs.sp;
+        self.pc = regs.pc;
+    }
+
+    /// Returns whether interrupts are enabled.
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    /// Enables or disables interrupts.
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+    }
+
     /// Reads AF register
     fn af(&self) -> u16 {
         (self.a as u16) << 8 | self.f as u16
@@ -248,14 +351,14 @@ impl CPU {
 
     /// Writes 8-bit value to memory
     fn write_mem8(&mut self, addr: u16, val: u8) {
-        self.mmu.write(addr, val);
+        self.bus.write(addr, val);
 
         self.tick += 4;
     }
 
     /// Reads 8-bit value from memory
     fn read_mem8(&mut self, addr: u16) -> u8 {
-        let ret = self.mmu.read(addr);
+        let ret = self.bus.read(addr);
 
         self.tick += 4;
 
@@ -1316,12 +1419,12 @@ impl CPU {
 
         total_tick += self.tick;
 
-        self.mmu.update(self.tick);
+        self.tick_bus();
 
         if self.ime {
             self.tick = 0;
             self.check_irqs();
-            self.mmu.update(self.tick);
+            self.tick_bus();
 
             total_tick += self.tick;
         }
@@ -1329,15 +1432,20 @@ impl CPU {
         total_tick
     }
 
+    /// Advances the bus by the M-cycles taken by the last instruction or
+    /// interrupt dispatch.
+    fn tick_bus(&mut self) {
+        self.bus.tick_cycles(self.tick / 4);
+    }
+
     /// Checks IRQs and execute ISRs if requested.
     fn check_irqs(&mut self) {
+        let pending = self.bus.pending_interrupts();
+
         // Bit 0 has the highest priority
         for i in 0..5 {
-            let irq = self.mmu.int_flag & (1 << i) > 0;
-            let ie = self.mmu.int_enable & (1 << i) > 0;
-
             // If interrupt is requested and enabled
-            if irq && ie {
+            if pending & (1 << i) > 0 {
                 self.call_isr(i);
                 break;
             }
@@ -1347,7 +1455,7 @@ impl CPU {
     /// Calls requested interrupt service routine.
     fn call_isr(&mut self, id: u8) {
         // Reset corresponding bit in IF
-        self.mmu.int_flag &= !(1 << id);
+        self.bus.ack_interrupt(id);
         // Clear IME (disable any further interrupts)
         self.ime = false;
         self.halted = false;
@@ -1356,8 +1464,8 @@ impl CPU {
             0 => 0x40,
             1 => 0x48,
             2 => 0x50,
-            3 => 0x80,
-            4 => 0x70,
+            3 => 0x58,
+            4 => 0x60,
             _ => panic!("Invalid IRQ id {}", id),
         };
 
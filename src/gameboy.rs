@@ -0,0 +1,196 @@
+use std::fs;
+use std::io;
+
+use catridge::Catridge;
+use cpu::CPU;
+use joypad::Key;
+use palette::Palette;
+use serial::SerialDevice;
+use state::{Decoder, Encoder, StateReader, StateWriter};
+
+/// Number of clocks in one frame (154 scanlines of 456 clocks each).
+pub const FRAME_TICKS: u32 = 456 * (144 + 10);
+
+/// A Game Boy.
+pub struct GameBoy {
+    /// CPU (owns the rest of the system through its `MMU`)
+    pub cpu: CPU,
+    /// Clocks elapsed beyond the end of the previous frame
+    overshoot: u32,
+    /// Palette used to convert the frame buffer to RGB
+    palette: Palette,
+}
+
+impl GameBoy {
+    /// Creates a new `GameBoy` with a given catridge inserted.
+    pub fn new(catridge: Catridge) -> Self {
+        GameBoy {
+            cpu: CPU::new(catridge),
+            overshoot: 0,
+            palette: Palette::default(),
+        }
+    }
+
+    /// Executes a single instruction and returns the number of elapsed clocks.
+    pub fn step(&mut self) -> u8 {
+        self.cpu.step()
+    }
+
+    /// Emulates one frame.
+    pub fn run_frame(&mut self) {
+        let mut elapsed_tick = self.overshoot;
+
+        while elapsed_tick < FRAME_TICKS {
+            elapsed_tick += self.cpu.step() as u32;
+        }
+
+        self.overshoot = elapsed_tick - FRAME_TICKS;
+    }
+
+    /// Returns the current contents of the frame buffer as shades from 0
+    /// (lightest) to 3 (darkest).
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.cpu.bus.ppu.frame_buffer()
+    }
+
+    /// Returns the frame buffer converted to RGB24 with the current palette.
+    pub fn frame_rgb(&self) -> Vec<u8> {
+        self.palette.apply(self.frame_buffer())
+    }
+
+    /// Returns the palette used by `frame_rgb`.
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Sets the palette used by `frame_rgb`.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /// Sets the sample rate of the audio output in Hz.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Takes the stereo audio samples (left and right interleaved) generated
+    /// since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    /// Reads a byte from the memory space.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.bus.read(addr)
+    }
+
+    /// Presses a key.
+    pub fn keydown(&mut self, key: Key) {
+        self.cpu.bus.joypad.keydown(key);
+    }
+
+    /// Releases a key.
+    pub fn keyup(&mut self, key: Key) {
+        self.cpu.bus.joypad.keyup(key);
+    }
+
+    /// Returns the keypress state of all keys.
+    pub fn key_state(&self) -> u8 {
+        self.cpu.bus.joypad.key_state()
+    }
+
+    /// Sets the keypress state of all keys.
+    pub fn set_key_state(&mut self, key_state: u8) {
+        self.cpu.bus.joypad.set_key_state(key_state);
+    }
+
+    /// Connects a device to the serial port, or disconnects it if `None`.
+    pub fn set_serial_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.cpu.bus.serial.set_device(device);
+    }
+
+    /// Returns the bytes sent through the serial port since they were last
+    /// taken. Test ROMs commonly report their results this way.
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.bus.serial.output()
+    }
+
+    /// Takes the bytes sent through the serial port since the last call.
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.cpu.bus.serial.take_output()
+    }
+
+    /// Returns whether the catridge's rumble motor is currently on.
+    pub fn rumble(&self) -> bool {
+        self.cpu.bus.catridge.rumble()
+    }
+
+    /// Loads external RAM from a save file.
+    pub fn read_save_file(&mut self, fname: &str) -> io::Result<()> {
+        self.cpu.bus.catridge.read_save_file(fname)
+    }
+
+    /// Writes external RAM to a save file.
+    pub fn write_save_file(&mut self, fname: &str) -> io::Result<()> {
+        self.cpu.bus.catridge.write_save_file(fname)
+    }
+
+    /// Writes external RAM to a save file if it has changed since it was
+    /// last saved.
+    pub fn autosave(&mut self, fname: &str) -> io::Result<()> {
+        if !self.cpu.bus.catridge.is_dirty() {
+            return Ok(());
+        }
+
+        self.write_save_file(fname)
+    }
+
+    /// Serializes the whole machine state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        let mut enc = Encoder::new();
+        enc.u32(self.overshoot);
+        writer.chunk(b"GB  ", &enc.finish());
+
+        self.cpu.save_state(&mut writer);
+        writer.finish()
+    }
+
+    /// Restores the whole machine state. The current state is kept if the
+    /// save state cannot be loaded.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let backup = self.save_state();
+
+        let res = self.restore(buf);
+        if res.is_err() {
+            self.restore(&backup)
+                .expect("Failed to roll back to the previous state");
+        }
+
+        res
+    }
+
+    fn restore(&mut self, buf: &[u8]) -> io::Result<()> {
+        let reader = StateReader::new(buf)?;
+
+        let mut dec = Decoder::new(reader.chunk(b"GB  ")?);
+        self.overshoot = dec.u32()?;
+
+        self.cpu.load_state(&reader)
+    }
+
+    /// Writes the machine state to a file.
+    pub fn save_state_file(&self, fname: &str) -> io::Result<()> {
+        info!("Writing save state to: {}", fname);
+
+        fs::write(fname, self.save_state())
+    }
+
+    /// Restores the machine state from a file.
+    pub fn load_state_file(&mut self, fname: &str) -> io::Result<()> {
+        info!("Reading save state from: {}", fname);
+
+        self.load_state(&fs::read(fname)?)
+    }
+}
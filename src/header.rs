@@ -0,0 +1,388 @@
+use catridge::CartridgeError;
+
+/// Nintendo logo displayed by the boot ROM (0x0104-0x0133).
+const NINTENDO_LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+/// Catridge header (0x0100-0x014f).
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    /// Title in upper case ASCII
+    pub title: String,
+    /// Manufacturer code (newer catridges only)
+    pub manufacturer_code: Option<String>,
+    /// CGB flag
+    pub cgb_flag: u8,
+    /// New licensee code
+    pub new_licensee_code: String,
+    /// SGB flag
+    pub sgb_flag: u8,
+    /// Catridge type
+    pub mbc_type: u8,
+    /// ROM size code
+    pub rom_size_code: u8,
+    /// RAM size code
+    pub ram_size_code: u8,
+    /// Destination code
+    pub destination: u8,
+    /// Old licensee code
+    pub old_licensee_code: u8,
+    /// Mask ROM version number
+    pub version: u8,
+    /// Header checksum
+    pub header_checksum: u8,
+    /// Global checksum
+    pub global_checksum: u16,
+    /// Whether the Nintendo logo is intact
+    pub logo_valid: bool,
+}
+
+/// Converts header bytes to a string, stopping at the first NUL.
+fn to_ascii(buf: &[u8]) -> String {
+    buf.iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+impl CartridgeHeader {
+    /// Parses the header of a ROM image.
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < 0x0150 {
+            return Err(CartridgeError::TruncatedRom {
+                expected: 0x0150,
+                actual: rom.len(),
+            });
+        }
+
+        let cgb_flag = rom[0x0143];
+
+        // On CGB-aware catridges the end of the title area holds the
+        // manufacturer code and the CGB flag
+        let (title, manufacturer_code) = if cgb_flag & 0x80 > 0 {
+            let code = &rom[0x013f..0x0143];
+            let code = if code
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+            {
+                Some(to_ascii(code))
+            } else {
+                None
+            };
+            (to_ascii(&rom[0x0134..0x013f]), code)
+        } else {
+            (to_ascii(&rom[0x0134..0x0144]), None)
+        };
+
+        Ok(CartridgeHeader {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            new_licensee_code: to_ascii(&rom[0x0144..0x0146]),
+            sgb_flag: rom[0x0146],
+            mbc_type: rom[0x0147],
+            rom_size_code: rom[0x0148],
+            ram_size_code: rom[0x0149],
+            destination: rom[0x014a],
+            old_licensee_code: rom[0x014b],
+            version: rom[0x014c],
+            header_checksum: rom[0x014d],
+            global_checksum: (rom[0x014e] as u16) << 8 | rom[0x014f] as u16,
+            logo_valid: rom[0x0104..0x0134] == NINTENDO_LOGO[..],
+        })
+    }
+
+    /// Returns the name of the catridge type.
+    pub fn mbc_name(&self) -> &'static str {
+        match self.mbc_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0b => "MMM01",
+            0x0c => "MMM01+RAM",
+            0x0d => "MMM01+RAM+BATTERY",
+            0x0f => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1a => "MBC5+RAM",
+            0x1b => "MBC5+RAM+BATTERY",
+            0x1c => "MBC5+RUMBLE",
+            0x1d => "MBC5+RUMBLE+RAM",
+            0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xfc => "POCKET CAMERA",
+            0xfd => "BANDAI TAMA5",
+            0xfe => "HuC3",
+            0xff => "HuC1+RAM+BATTERY",
+            _ => "Unknown",
+        }
+    }
+
+    /// Returns whether the catridge has a battery that keeps its RAM (and
+    /// clock) contents while powered off.
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.mbc_type,
+            0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff
+        )
+    }
+
+    /// Returns the ROM size in bytes, or `None` if the code is invalid.
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.rom_size_code {
+            0x00..=0x08 => (32 * 1024usize).checked_shl(self.rom_size_code as u32),
+            // Sizes used by a few unofficial catridges (72, 80 and 96 banks)
+            0x52 => Some(72 * 16 * 1024),
+            0x53 => Some(80 * 16 * 1024),
+            0x54 => Some(96 * 16 * 1024),
+            _ => None,
+        }
+    }
+
+    /// Returns the external RAM size in bytes, or `None` if the code is
+    /// invalid.
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0 => Some(0),
+            1 => Some(2 * 1024),
+            2 => Some(8 * 1024),
+            3 => Some(32 * 1024),
+            4 => Some(128 * 1024),
+            5 => Some(64 * 1024),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the game supports CGB functions.
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 > 0
+    }
+
+    /// Returns whether the game works on CGB only.
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xc0
+    }
+
+    /// Returns whether the game supports SGB functions.
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    /// Returns whether the game is sold in Japan.
+    pub fn is_japanese(&self) -> bool {
+        self.destination == 0x00
+    }
+
+    /// Returns the licensee code, taking the new licensee code into account
+    /// when the old one refers to it.
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    /// Computes the header checksum over a ROM image.
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        let mut chksum: u8 = 0;
+        for b in &rom[0x0134..0x014d] {
+            chksum = chksum.wrapping_sub(*b).wrapping_sub(1);
+        }
+        chksum
+    }
+
+    /// Computes the global checksum over a ROM image.
+    pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+        let mut chksum: u16 = 0;
+        for (i, b) in rom.iter().enumerate() {
+            if i != 0x014e && i != 0x014f {
+                chksum = chksum.wrapping_add(*b as u16);
+            }
+        }
+        chksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a ROM with a valid logo and header checksum, and a given
+    /// title area (0x0134-0x0143).
+    fn rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014d] = CartridgeHeader::compute_header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn truncated() {
+        match CartridgeHeader::parse(&[0; 0x014f]) {
+            Err(CartridgeError::TruncatedRom { expected, actual }) => {
+                assert_eq!(expected, 0x0150);
+                assert_eq!(actual, 0x014f);
+            }
+            _ => panic!("Expected a truncated ROM error"),
+        }
+    }
+
+    #[test]
+    fn old_title() {
+        let header = CartridgeHeader::parse(&rom(b"SIXTEEN CHAR ABC")).unwrap();
+
+        assert_eq!(header.title, "SIXTEEN CHAR ABC");
+        assert_eq!(header.manufacturer_code, None);
+        assert!(!header.supports_cgb());
+        assert!(header.logo_valid);
+    }
+
+    #[test]
+    fn title_stops_at_nul() {
+        let header = CartridgeHeader::parse(&rom(b"TETRIS\0\0\0\0JUNK")).unwrap();
+        assert_eq!(header.title, "TETRIS");
+    }
+
+    #[test]
+    fn cgb_title() {
+        let header = CartridgeHeader::parse(&rom(b"POKEMON CRYBYTE\xc0")).unwrap();
+
+        assert_eq!(header.title, "POKEMON CRY");
+        assert_eq!(header.manufacturer_code, Some(String::from("BYTE")));
+        assert_eq!(header.cgb_flag, 0xc0);
+        assert!(header.supports_cgb());
+        assert!(header.cgb_only());
+
+        // A manufacturer code must be upper case letters and digits
+        let header = CartridgeHeader::parse(&rom(b"ZELDA\0\0\0\0\0\0ab\0\0\x80")).unwrap();
+        assert_eq!(header.title, "ZELDA");
+        assert_eq!(header.manufacturer_code, None);
+        assert!(header.supports_cgb());
+        assert!(!header.cgb_only());
+    }
+
+    #[test]
+    fn licensee_code() {
+        let mut rom = rom(b"TEST");
+        rom[0x0144] = b'0';
+        rom[0x0145] = b'1';
+        rom[0x014b] = 0x01;
+
+        // The old code is used unless it refers to the new one
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.new_licensee_code, "01");
+        assert_eq!(header.licensee_code(), "01");
+        rom[0x014b] = 0xa4;
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().licensee_code(), "A4");
+
+        rom[0x014b] = 0x33;
+        rom[0x0144] = b'9';
+        rom[0x0145] = b'Z';
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.licensee_code(), "9Z");
+
+        // SGB support requires the new licensee code
+        rom[0x0146] = 0x03;
+        assert!(CartridgeHeader::parse(&rom).unwrap().supports_sgb());
+        rom[0x014b] = 0x01;
+        assert!(!CartridgeHeader::parse(&rom).unwrap().supports_sgb());
+    }
+
+    #[test]
+    fn header_checksum() {
+        let mut rom = rom(b"CHECKSUM");
+        rom[0x0147] = 0x13;
+        rom[0x0148] = 0x05;
+        rom[0x014d] = CartridgeHeader::compute_header_checksum(&rom);
+
+        let sum = rom[0x0134..0x014d]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b).wrapping_add(1));
+        assert_eq!(rom[0x014d], sum.wrapping_neg());
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.header_checksum, rom[0x014d]);
+
+        // Bytes outside 0x0134-0x014c are not covered
+        rom[0x0100] = 0xff;
+        rom[0x014e] = 0xff;
+        assert_eq!(
+            CartridgeHeader::compute_header_checksum(&rom),
+            header.header_checksum
+        );
+        rom[0x014c] = 0x01;
+        assert_eq!(
+            CartridgeHeader::compute_header_checksum(&rom),
+            header.header_checksum.wrapping_sub(1)
+        );
+    }
+
+    #[test]
+    fn global_checksum() {
+        let mut rom = rom(b"GLOBAL");
+        rom[0x014e] = 0x12;
+        rom[0x014f] = 0x34;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.global_checksum, 0x1234);
+
+        // The checksum bytes themselves are not covered
+        let sum = CartridgeHeader::compute_global_checksum(&rom);
+        rom[0x014e] = 0xff;
+        assert_eq!(CartridgeHeader::compute_global_checksum(&rom), sum);
+        rom[0x7fff] = 0x01;
+        assert_eq!(CartridgeHeader::compute_global_checksum(&rom), sum + 1);
+    }
+
+    #[test]
+    fn rom_size() {
+        let mut rom = rom(b"SIZE");
+        let sizes = [
+            (0x00, Some(32 * 1024)),
+            (0x05, Some(1024 * 1024)),
+            (0x08, Some(8 * 1024 * 1024)),
+            (0x09, None),
+            (0x52, Some(1152 * 1024)),
+            (0x53, Some(1280 * 1024)),
+            (0x54, Some(1536 * 1024)),
+            (0x55, None),
+        ];
+
+        for &(code, size) in &sizes {
+            rom[0x0148] = code;
+            assert_eq!(CartridgeHeader::parse(&rom).unwrap().rom_size(), size);
+        }
+    }
+
+    #[test]
+    fn ram_size() {
+        let mut rom = rom(b"SIZE");
+        rom[0x0149] = 0x05;
+        assert_eq!(
+            CartridgeHeader::parse(&rom).unwrap().ram_size(),
+            Some(64 * 1024)
+        );
+        rom[0x0149] = 0x06;
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().ram_size(), None);
+    }
+}
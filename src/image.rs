@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use png;
+
+/// Writes an RGB24 image to a PNG file.
+pub fn write_png(fname: &str, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(fname)?);
+
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+
+    Ok(())
+}
+
+/// Writes an RGB24 image to a binary PPM file.
+pub fn write_ppm(fname: &str, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(fname)?);
+
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    file.write_all(rgb)?;
+
+    Ok(())
+}
+
+/// Writes an RGB24 image to a file. The format is chosen from the extension
+/// (`.ppm` for PPM, PNG otherwise).
+pub fn write_image(fname: &str, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    match Path::new(fname).extension().and_then(|ext| ext.to_str()) {
+        Some("ppm") => write_ppm(fname, width, height, rgb),
+        _ => write_png(fname, width, height, rgb),
+    }
+}
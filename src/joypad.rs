@@ -1,4 +1,7 @@
+use std::io;
+
 use io_device::IODevice;
+use state::{Decoder, Encoder};
 
 /// Joypad
 pub struct Joypad {
@@ -10,7 +13,7 @@ pub struct Joypad {
     pub irq: bool,
 }
 
-#[derive(Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub enum Key {
     Down,
     Up,
@@ -32,6 +35,40 @@ impl Joypad {
         }
     }
 
+    /// Serializes the joypad state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.u8(self.joyp);
+        enc.u8(self.key_state);
+        enc.bool(self.irq);
+        enc.finish()
+    }
+
+    /// Restores the joypad state.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+        self.joyp = dec.u8()?;
+        self.key_state = dec.u8()?;
+        self.irq = dec.bool()?;
+
+        Ok(())
+    }
+
+    /// Returns the keypress state. A bit is cleared while its key is pressed.
+    pub fn key_state(&self) -> u8 {
+        self.key_state
+    }
+
+    /// Sets the keypress state of all keys at once.
+    pub fn set_key_state(&mut self, key_state: u8) {
+        // Interrupt is requested when any key is newly pressed
+        if self.key_state & !key_state > 0 {
+            self.irq = true;
+        }
+
+        self.key_state = key_state;
+    }
+
     pub fn keydown(&mut self, key: Key) {
         match key {
             Key::Down => self.key_state &= !0x80,
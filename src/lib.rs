@@ -0,0 +1,50 @@
+//! Game Boy emulator core.
+//!
+//! The core has no dependency on any particular frontend. Embedders drive the
+//! emulator through [`GameBoy`](struct.GameBoy.html), or reach into the
+//! individual components for finer-grained control.
+
+#![allow(
+    clippy::new_without_default,
+    clippy::upper_case_acronyms,
+    clippy::wildcard_in_or_patterns
+)]
+
+extern crate flate2;
+extern crate gif;
+#[macro_use]
+extern crate log;
+extern crate png;
+extern crate zip;
+
+pub mod apu;
+pub mod bus;
+pub mod catridge;
+pub mod cpu;
+mod gameboy;
+pub mod header;
+pub mod image;
+pub mod io_device;
+pub mod joypad;
+pub mod link;
+pub mod loader;
+pub mod mapper;
+pub mod mmu;
+pub mod movie;
+pub mod pacing;
+pub mod palette;
+pub mod patch;
+pub mod ppu;
+pub mod printer;
+pub mod rewind;
+pub mod savefile;
+pub mod serial;
+pub mod state;
+pub mod timer;
+pub mod video;
+
+pub use catridge::{CartridgeError, Catridge};
+pub use gameboy::GameBoy;
+pub use header::CartridgeHeader;
+pub use joypad::Key;
+pub use ppu::{SCREEN_H, SCREEN_W};
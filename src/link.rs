@@ -0,0 +1,158 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use serial::SerialDevice;
+
+/// Message sent by the side driving the clock.
+const MSG_TRANSFER: u8 = 0;
+/// Message sent in reply to a transfer.
+const MSG_REPLY: u8 = 1;
+
+/// How long to wait for the peer to reply to a transfer.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Link cable to another emulator instance over TCP.
+///
+/// Each transfer is a two-byte message (kind and data). The side driving the
+/// clock sends a transfer and polls for the reply, while the other side
+/// replies with its serial data once it is waiting for the external clock.
+/// The socket is nonblocking so that the emulation never waits for the peer.
+pub struct TcpLink {
+    stream: TcpStream,
+    /// Bytes received but not yet processed
+    recv_buf: Vec<u8>,
+    /// Transfer received from the peer that has not been replied yet
+    pending: Option<u8>,
+    /// When the transfer awaiting a reply was sent
+    sent_at: Option<Instant>,
+}
+
+impl TcpLink {
+    /// Waits for a peer to connect on a given address.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Waiting for link peer on {}", listener.local_addr()?);
+
+        let (stream, peer) = listener.accept()?;
+        info!("Link peer connected from {}", peer);
+
+        Self::from_stream(stream)
+    }
+
+    /// Connects to a peer listening on a given address.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        info!("Connected to link peer {}", stream.peer_addr()?);
+
+        Self::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(TcpLink {
+            stream,
+            recv_buf: Vec::new(),
+            pending: None,
+            sent_at: None,
+        })
+    }
+
+    /// Sends a message to the peer.
+    fn send(&mut self, kind: u8, val: u8) -> io::Result<()> {
+        self.stream.write_all(&[kind, val])
+    }
+
+    /// Receives the next message from the peer, if any.
+    fn recv(&mut self) -> io::Result<Option<(u8, u8)>> {
+        while self.recv_buf.len() < 2 {
+            let mut buf = [0; 64];
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Link peer disconnected",
+                    ))
+                }
+                Ok(n) => self.recv_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let msg = (self.recv_buf[0], self.recv_buf[1]);
+        self.recv_buf.drain(..2);
+
+        Ok(Some(msg))
+    }
+
+    /// Receives the reply to a transfer, if it has arrived. Transfers started
+    /// by the peer in the meantime are kept for `poll_external`.
+    fn recv_reply(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.recv()? {
+                Some((MSG_REPLY, val)) => return Ok(Some(val)),
+                Some((_, val)) => self.pending = Some(val),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, val: u8) -> Option<u8> {
+        if let Err(e) = self.send(MSG_TRANSFER, val) {
+            warn!("Link transfer failed: {}", e);
+            return Some(0xff);
+        }
+
+        self.sent_at = Some(Instant::now());
+        self.poll_reply()
+    }
+
+    fn poll_reply(&mut self) -> Option<u8> {
+        let timed_out = self
+            .sent_at
+            .map_or(true, |sent_at| sent_at.elapsed() >= REPLY_TIMEOUT);
+
+        let val = match self.recv_reply() {
+            Ok(Some(val)) => val,
+            Ok(None) if timed_out => {
+                warn!("Link peer did not reply");
+                0xff
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Link transfer failed: {}", e);
+                0xff
+            }
+        };
+
+        self.sent_at = None;
+        Some(val)
+    }
+
+    fn poll_external(&mut self, val: u8) -> Option<u8> {
+        while self.pending.is_none() {
+            match self.recv() {
+                Ok(Some((MSG_TRANSFER, data))) => self.pending = Some(data),
+                // A late reply to a transfer that timed out
+                Ok(Some(_)) => (),
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Link transfer failed: {}", e);
+                    return None;
+                }
+            }
+        }
+
+        if let Err(e) = self.send(MSG_REPLY, val) {
+            warn!("Link transfer failed: {}", e);
+            return None;
+        }
+
+        self.pending.take()
+    }
+}
@@ -0,0 +1,105 @@
+//! Reading ROM images from plain files and compressed archives, and applying
+//! soft patches.
+
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use catridge::CartridgeError;
+use patch;
+
+/// Reads a ROM image from a file, decompressing gzip and zip archives.
+///
+/// For zip archives, `entry` selects the file to extract. If it is `None`, the
+/// first `.gb` or `.gbc` file in the archive is used.
+pub fn read_rom(fname: &str, entry: Option<&str>) -> Result<Vec<u8>, CartridgeError> {
+    let mut buf = Vec::new();
+    File::open(fname)?.read_to_end(&mut buf)?;
+
+    decompress(buf, entry)
+}
+
+/// Decompresses a ROM image if it is a gzip or zip archive. Other data is
+/// returned as is.
+pub fn decompress(buf: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, CartridgeError> {
+    if buf.starts_with(&[0x1f, 0x8b]) {
+        gunzip(&buf)
+    } else if buf.starts_with(b"PK\x03\x04") {
+        unzip(buf, entry)
+    } else {
+        Ok(buf)
+    }
+}
+
+/// Decompresses a gzip stream.
+fn gunzip(buf: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut rom = Vec::new();
+    GzDecoder::new(buf).read_to_end(&mut rom)?;
+
+    Ok(rom)
+}
+
+/// Returns whether a file name looks like a ROM image.
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+/// Extracts a ROM image from a zip archive.
+fn unzip(buf: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, CartridgeError> {
+    let archive_err = |e| CartridgeError::Archive(format!("{}", e));
+    let mut archive = ZipArchive::new(Cursor::new(buf)).map_err(archive_err)?;
+
+    let name = match entry {
+        Some(name) => name.to_string(),
+        None => {
+            let mut found = None;
+
+            for i in 0..archive.len() {
+                let name = archive.by_index(i).map_err(archive_err)?.name().to_string();
+
+                if is_rom_name(&name) {
+                    found = Some(name);
+                    break;
+                }
+            }
+
+            found.ok_or_else(|| CartridgeError::Archive(String::from("No ROM found in archive")))?
+        }
+    };
+
+    info!("Extracting {} from archive", name);
+
+    let mut file = archive.by_name(&name).map_err(archive_err)?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)?;
+
+    Ok(rom)
+}
+
+/// Returns the path of an IPS, UPS or BPS patch with the same name as a ROM
+/// file, if one exists.
+pub fn find_patch(fname: &str) -> Option<String> {
+    ["ips", "ups", "bps"]
+        .iter()
+        .map(|ext| Path::new(fname).with_extension(ext))
+        .find(|path| path.is_file())
+        .and_then(|path| path.to_str().map(|s| s.to_string()))
+}
+
+/// Applies a patch file to a ROM image and recomputes the header checksums.
+pub fn apply_patch_file(rom: &[u8], patch_fname: &str) -> Result<Vec<u8>, CartridgeError> {
+    info!("Applying patch {}", patch_fname);
+
+    let mut buf = Vec::new();
+    File::open(patch_fname)?.read_to_end(&mut buf)?;
+
+    let mut rom = patch::apply(rom, &buf)?;
+    patch::fix_checksums(&mut rom);
+
+    Ok(rom)
+}
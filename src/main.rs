@@ -1,54 +1,171 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-#[macro_use]
-extern crate log;
+extern crate ctrlc;
 extern crate env_logger;
+extern crate gbr;
 extern crate sdl2;
 
-use std::thread;
-use std::time;
-
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::PixelFormatEnum;
 
-mod catridge;
-mod cpu;
-mod io_device;
-mod joypad;
-mod mmu;
-mod ppu;
-mod timer;
+use gbr::link::TcpLink;
+use gbr::movie::Movie;
+use gbr::pacing::Pacer;
+use gbr::palette::Palette;
+use gbr::printer::Printer;
+use gbr::rewind::Rewind;
+use gbr::video::VideoRecorder;
+use gbr::{Catridge, GameBoy, Key, SCREEN_H, SCREEN_W};
+
+/// Number of frames between two rewind snapshots.
+const REWIND_INTERVAL: u32 = 4;
+/// Default memory budget of the rewind buffer in MiB.
+const REWIND_BUDGET_MB: usize = 64;
+/// Number of frames between two frame buffer hashes in recorded movies.
+const MOVIE_HASH_INTERVAL: u32 = 60;
+/// Number of frames between two checks whether external RAM needs saving.
+const AUTOSAVE_INTERVAL: u32 = 60;
+/// Audio output sample rate in Hz.
+const SAMPLE_RATE: i32 = 48_000;
+/// Maximum audio latency in seconds. Samples are dropped beyond this.
+const MAX_AUDIO_LATENCY: f32 = 0.1;
 
-/// Translates keycode to `joypad::Key` enum.
-fn translate_keycode(key: Keycode) -> Option<joypad::Key> {
+/// Translates keycode to `Key` enum.
+fn translate_keycode(key: Keycode) -> Option<Key> {
     match key {
-        Keycode::Down => Some(joypad::Key::Down),
-        Keycode::Up => Some(joypad::Key::Up),
-        Keycode::Left => Some(joypad::Key::Left),
-        Keycode::Right => Some(joypad::Key::Right),
-        Keycode::Return => Some(joypad::Key::Start),
-        Keycode::RShift => Some(joypad::Key::Select),
-        Keycode::X => Some(joypad::Key::A),
-        Keycode::Z => Some(joypad::Key::B),
+        Keycode::Down => Some(Key::Down),
+        Keycode::Up => Some(Key::Up),
+        Keycode::Left => Some(Key::Left),
+        Keycode::Right => Some(Key::Right),
+        Keycode::Return => Some(Key::Start),
+        Keycode::RShift => Some(Key::Select),
+        Keycode::X => Some(Key::A),
+        Keycode::Z => Some(Key::B),
         _ => None,
     }
 }
 
 /// Handles key down event.
-fn handle_keydown(cpu: &mut cpu::CPU, key: Keycode) {
-    translate_keycode(key).map(|k| cpu.mmu.joypad.keydown(k));
+fn handle_keydown(gameboy: &mut GameBoy, key: Keycode) {
+    if let Some(k) = translate_keycode(key) {
+        gameboy.keydown(k);
+    }
 }
 
 /// Handles key up event.
-fn handle_keyup(cpu: &mut cpu::CPU, key: Keycode) {
-    translate_keycode(key).map(|k| cpu.mmu.joypad.keyup(k));
+fn handle_keyup(gameboy: &mut GameBoy, key: Keycode) {
+    if let Some(k) = translate_keycode(key) {
+        gameboy.keyup(k);
+    }
+}
+
+/// Returns the save state slot bound to a function key.
+fn state_slot(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        Keycode::F9 => Some(9),
+        _ => None,
+    }
+}
+
+/// Saves the state to a slot if shift is held, loads it otherwise.
+fn handle_state_key(gameboy: &mut GameBoy, slot: u8, keymod: Mod) {
+    let fname = state_fname(slot);
+
+    let res = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        gameboy.save_state_file(&fname)
+    } else {
+        gameboy.load_state_file(&fname)
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}: {}", fname, e);
+    }
+}
+
+/// Returns whether a key controls the emulation speed.
+fn is_speed_key(key: Keycode) -> bool {
+    matches!(
+        key,
+        Keycode::Tab
+            | Keycode::Equals
+            | Keycode::KpPlus
+            | Keycode::Minus
+            | Keycode::KpMinus
+            | Keycode::Num0
+            | Keycode::Kp0
+    )
+}
+
+/// Handles speed control key events.
+fn handle_speed_key(pacer: &mut Pacer, key: Keycode, down: bool) {
+    match key {
+        // Fast-forward while held
+        Keycode::Tab => pacer.set_uncapped(down),
+        Keycode::Equals | Keycode::KpPlus if down => pacer.faster(),
+        Keycode::Minus | Keycode::KpMinus if down => pacer.slower(),
+        Keycode::Num0 | Keycode::Kp0 if down => pacer.reset_speed(),
+        _ => (),
+    }
+}
+
+/// Returns the window title for the current speed.
+fn window_title(pacer: &Pacer) -> String {
+    match pacer.speed() {
+        Some(speed) if (speed - 1.0).abs() < 1e-6 => String::from("gbr"),
+        Some(speed) => format!("gbr ({}x)", speed),
+        None => String::from("gbr (fast-forward)"),
+    }
 }
 
 /// Returns ROM filename.
 fn rom_fname() -> String {
-    env::args().nth(1).unwrap()
+    env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap()
+}
+
+/// Returns whether the ROM should be loaded even if its header is invalid.
+fn force_load() -> bool {
+    env::args().any(|arg| arg == "--force")
+}
+
+/// Returns the value of an option given as `--name=VALUE`.
+fn option_value(name: &str) -> Option<String> {
+    let prefix = format!("--{}=", name);
+
+    env::args()
+        .find(|arg| arg.starts_with(&prefix))
+        .map(|arg| arg[prefix.len()..].to_string())
+}
+
+/// Returns the memory budget of the rewind buffer in bytes, which can be set
+/// with `--rewind-budget=MB`.
+fn rewind_budget() -> usize {
+    let mb = option_value("rewind-budget").map_or(REWIND_BUDGET_MB, |val| {
+        val.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid rewind budget: {}", val);
+            process::exit(1);
+        })
+    });
+
+    mb * 1024 * 1024
 }
 
 /// Returns save filename for current ROM.
@@ -58,6 +175,119 @@ fn save_fname() -> String {
     path_buf.to_str().unwrap().to_string()
 }
 
+/// Returns the path to record video to, which is given with `--video=PATH` or
+/// else the first unused `<rom>-N.gif`.
+fn video_path() -> String {
+    if let Some(path) = option_value("video") {
+        return path;
+    }
+
+    let rom_path = PathBuf::from(rom_fname());
+    let stem = rom_path.file_stem().unwrap().to_string_lossy();
+
+    (1..)
+        .map(|n| {
+            rom_path
+                .with_file_name(format!("{}-{}.gif", stem, n))
+                .to_string_lossy()
+                .to_string()
+        })
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+/// Starts recording video if not recording, stops it otherwise.
+fn toggle_video(video: &mut Option<VideoRecorder>) {
+    match video.take() {
+        Some(recorder) => {
+            let frames = recorder.frames();
+
+            match recorder.finish() {
+                Ok(()) => eprintln!("Recorded {} frames", frames),
+                Err(e) => eprintln!("Failed to record video: {}", e),
+            }
+        }
+        None => {
+            let path = video_path();
+
+            match VideoRecorder::create(&path) {
+                Ok(recorder) => {
+                    eprintln!("Recording video to {}", path);
+                    *video = Some(recorder);
+                }
+                Err(e) => eprintln!("{}: {}", path, e),
+            }
+        }
+    }
+}
+
+/// Opens the link cable to another instance given by `--link-listen=ADDR`
+/// or `--link-connect=ADDR`, if any.
+fn open_link() -> Option<TcpLink> {
+    let (addr, res) = if let Some(addr) = option_value("link-listen") {
+        eprintln!("Waiting for link peer on {}", addr);
+        let res = TcpLink::listen(addr.as_str());
+        (addr, res)
+    } else if let Some(addr) = option_value("link-connect") {
+        let res = TcpLink::connect(addr.as_str());
+        (addr, res)
+    } else {
+        return None;
+    };
+
+    match res {
+        Ok(link) => Some(link),
+        Err(e) => {
+            eprintln!("{}: {}", addr, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns all palettes, including those from `--palettes=FILE`.
+fn load_palettes() -> Vec<Palette> {
+    let config = option_value("palettes");
+
+    match Palette::load_all(config.as_deref()) {
+        Ok(palettes) => palettes,
+        Err(e) => {
+            eprintln!("{}: {}", config.as_deref().unwrap_or("palette config"), e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns save state filename for a given slot.
+fn state_fname(slot: u8) -> String {
+    let mut path_buf = PathBuf::from(rom_fname());
+    path_buf.set_extension(format!("ss{}", slot));
+    path_buf.to_str().unwrap().to_string()
+}
+
+/// Opens the audio output, or returns `None` if no audio device is available.
+fn open_audio(sdl_context: &sdl2::Sdl) -> Option<AudioQueue<f32>> {
+    let spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(2),
+        samples: Some(1024),
+    };
+
+    let queue = sdl_context
+        .audio()
+        .and_then(|audio| audio.open_queue::<f32, _>(None, &spec));
+
+    match queue {
+        Ok(queue) => {
+            queue.resume();
+            Some(queue)
+        }
+        Err(e) => {
+            eprintln!("Failed to open audio device: {}", e);
+            None
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -75,43 +305,166 @@ fn main() {
     let texture_creator = canvas.texture_creator();
 
     let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+        .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_W as u32, SCREEN_H as u32)
         .unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let mut cpu = cpu::CPU::new(&rom_fname());
+    let catridge = match Catridge::new(&rom_fname(), force_load()) {
+        Ok(catridge) => catridge,
+        Err(e) => {
+            eprintln!("{}: {}", rom_fname(), e);
+            process::exit(1);
+        }
+    };
+
+    let mut gameboy = GameBoy::new(catridge);
+
+    // Palette is chosen with `--palette=NAME` and cycled through with P
+    let palettes = load_palettes();
+    let mut palette_index = match option_value("palette") {
+        Some(name) => match palettes.iter().position(|palette| palette.name == name) {
+            Some(index) => index,
+            None => {
+                eprintln!("Unknown palette: {}", name);
+                process::exit(1);
+            }
+        },
+        None => 0,
+    };
+    gameboy.set_palette(palettes[palette_index].clone());
+
+    let audio_queue = open_audio(&sdl_context);
+    if let Some(ref queue) = audio_queue {
+        gameboy.set_sample_rate(queue.spec().freq as u32);
+    }
+
+    if let Err(e) = gameboy.read_save_file(&save_fname()) {
+        eprintln!("{}: {}", save_fname(), e);
+        process::exit(1);
+    }
+
+    // The serial port is connected to a printer with `--printer=DIR` or to
+    // another instance with `--link-listen=ADDR` or `--link-connect=ADDR`
+    if let Some(dir) = option_value("printer") {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!("{}: {}", dir, e);
+            process::exit(1);
+        }
+        gameboy.set_serial_device(Some(Box::new(Printer::new(dir))));
+    } else if let Some(link) = open_link() {
+        gameboy.set_serial_device(Some(Box::new(link)));
+    }
+
+    // Quit cleanly (and write the save file) on Ctrl-C
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst)).unwrap();
+    let mut autosave_countdown = AUTOSAVE_INTERVAL;
 
-    cpu.mmu.catridge.read_save_file(&save_fname());
+    let mut rewind = Rewind::new(REWIND_INTERVAL, rewind_budget());
+    let mut rewinding = false;
+
+    // Inputs are recorded to `--record=FILE` or replayed from `--play=FILE`
+    let record_fname = option_value("record");
+    let mut recording = record_fname
+        .as_ref()
+        .map(|_| Movie::new(&gameboy, MOVIE_HASH_INTERVAL));
+    let mut playback = option_value("play").map(|fname| {
+        match Movie::load(&fname).and_then(|movie| movie.rewind(&mut gameboy).map(|_| movie)) {
+            Ok(movie) => movie,
+            Err(e) => {
+                eprintln!("{}: {}", fname, e);
+                process::exit(1);
+            }
+        }
+    });
+    playback = playback.filter(|movie| !movie.is_empty());
+    let mut movie_frame = 0;
+
+    // Video is recorded from the start with `--video=PATH` or toggled with F12
+    let mut video = None;
+    if option_value("video").is_some() {
+        toggle_video(&mut video);
+    }
+
+    let mut pacer = Pacer::new();
+    let mut render = true;
 
     'running: loop {
-        let now = time::Instant::now();
-        let mut elapsed_tick: u32 = 0;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(ref mut movie) = recording {
+                movie.record_frame(&mut gameboy);
+            } else if let Some(movie) = playback.take() {
+                if let Err(e) = movie.play_frame(movie_frame, &mut gameboy) {
+                    eprintln!("{}", e);
+                }
+
+                // Hand control back to the player at the end of the movie
+                movie_frame += 1;
+                if movie_frame < movie.len() {
+                    playback = Some(movie);
+                }
+            } else if rewinding {
+                // Step back to the previous snapshot
+                rewind.pop(&mut gameboy);
+            } else {
+                // Emulate one frame
+                gameboy.run_frame();
+                rewind.push(&gameboy);
+            }
+        }));
+
+        // Save progress before going down if the emulator crashed
+        if let Err(payload) = res {
+            if let Err(e) = gameboy.write_save_file(&save_fname()) {
+                eprintln!("{}: {}", save_fname(), e);
+            }
+            panic::resume_unwind(payload);
+        }
+
+        if let Some(ref mut recorder) = video {
+            if let Err(e) = recorder.add_frame(&gameboy.frame_rgb()) {
+                eprintln!("Failed to record video: {}", e);
+                video = None;
+            }
+        }
+
+        autosave_countdown -= 1;
+        if autosave_countdown == 0 {
+            autosave_countdown = AUTOSAVE_INTERVAL;
 
-        // Emulate one frame
-        while elapsed_tick < 456 * (144 + 10) {
-            elapsed_tick += cpu.step() as u32;
+            if let Err(e) = gameboy.autosave(&save_fname()) {
+                eprintln!("{}: {}", save_fname(), e);
+            }
         }
 
-        texture
-            .with_lock(None, |buf: &mut [u8], pitch: usize| {
-                let fb = cpu.mmu.ppu.frame_buffer();
+        let samples = gameboy.take_samples();
+        if let Some(ref queue) = audio_queue {
+            // Queue size is in bytes (two channels of 4-byte samples)
+            let spec = queue.spec();
+            let max_size = (spec.freq as f32 * MAX_AUDIO_LATENCY) as u32 * 2 * 4;
+
+            if queue.size() < max_size {
+                queue.queue(&samples);
+            }
+        }
 
-                for y in 0..144 {
-                    for x in 0..160 {
-                        let offset = y * pitch + x * 3;
-                        let color = fb[y * 160 + x];
+        if render {
+            texture
+                .with_lock(None, |buf: &mut [u8], pitch: usize| {
+                    let rgb = gameboy.frame_rgb();
+                    let row_len = SCREEN_W as usize * 3;
 
-                        buf[offset] = color;
-                        buf[offset + 1] = color;
-                        buf[offset + 2] = color;
+                    for (y, row) in rgb.chunks(row_len).enumerate() {
+                        buf[y * pitch..y * pitch + row_len].copy_from_slice(row);
                     }
-                }
-            })
-            .unwrap();
+                })
+                .unwrap();
 
-        canvas.clear();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+            canvas.clear();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
 
         for event in event_pump.poll_iter() {
             match event {
@@ -120,25 +473,76 @@ fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => toggle_video(&mut video),
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    ..
+                } => {
+                    palette_index = (palette_index + 1) % palettes.len();
+                    gameboy.set_palette(palettes[palette_index].clone());
+                    eprintln!("Palette: {}", palettes[palette_index].name);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
                 Event::KeyDown {
                     keycode: Some(keycode),
                     ..
-                } => handle_keydown(&mut cpu, keycode),
+                } if is_speed_key(keycode) => {
+                    handle_speed_key(&mut pacer, keycode, true);
+                    let title = window_title(&pacer);
+                    canvas.window_mut().set_title(&title).unwrap();
+                }
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => handle_keyup(&mut cpu, keycode),
+                } if is_speed_key(keycode) => {
+                    handle_speed_key(&mut pacer, keycode, false);
+                    let title = window_title(&pacer);
+                    canvas.window_mut().set_title(&title).unwrap();
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } => match state_slot(keycode) {
+                    Some(slot) => handle_state_key(&mut gameboy, slot, keymod),
+                    None => handle_keydown(&mut gameboy, keycode),
+                },
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => handle_keyup(&mut gameboy, keycode),
                 _ => (),
             }
         }
 
-        let wait = time::Duration::from_micros(1000000 / 60);
-        let elapsed = now.elapsed();
+        if interrupted.load(Ordering::SeqCst) {
+            break 'running;
+        }
+
+        render = pacer.end_frame();
+    }
 
-        if wait > elapsed {
-            thread::sleep(wait - elapsed);
+    if let Err(e) = gameboy.write_save_file(&save_fname()) {
+        eprintln!("{}: {}", save_fname(), e);
+    }
+
+    if let (Some(fname), Some(movie)) = (record_fname, recording) {
+        if let Err(e) = movie.save(&fname) {
+            eprintln!("{}: {}", fname, e);
         }
     }
 
-    cpu.mmu.catridge.write_save_file(&save_fname());
+    if video.is_some() {
+        toggle_video(&mut video);
+    }
 }
@@ -0,0 +1,109 @@
+use std::io;
+
+use super::{check_state_len, read_ram_bank, read_rom_bank, write_ram_bank, Mapper};
+
+/// MBC1 (up to 2MB ROM and 32KB RAM).
+pub struct Mbc1 {
+    ram_enable: bool,
+    bank_no_upper: u8,
+    bank_no_lower: u8,
+    mode: bool,
+}
+
+impl Mbc1 {
+    /// Creates a new `Mbc1`.
+    pub fn new() -> Self {
+        Mbc1 {
+            ram_enable: false,
+            bank_no_upper: 0,
+            bank_no_lower: 0,
+            mode: false,
+        }
+    }
+
+    fn rom_bank_no(&self) -> usize {
+        let bank_no = if self.mode {
+            self.bank_no_lower
+        } else {
+            self.bank_no_upper << 5 | self.bank_no_lower
+        };
+
+        let bank_no = match bank_no {
+            0 | 0x20 | 0x40 | 0x60 => bank_no + 1,
+            _ => bank_no,
+        };
+
+        bank_no as usize
+    }
+
+    fn ram_bank_no(&self) -> usize {
+        if self.mode {
+            self.bank_no_upper as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => rom[addr as usize],
+            // ROM bank 01-7f
+            _ => read_rom_bank(rom, self.rom_bank_no(), addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM enable
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            // ROM bank number (lower 5 bits)
+            0x2000..=0x3fff => self.bank_no_lower = val & 0x1f,
+            // RAM bank number or ROM bank number (upper 2 bits)
+            0x4000..=0x5fff => self.bank_no_upper = val & 0x03,
+            // ROM/RAM mode select
+            0x6000..=0x7fff => self.mode = val & 0x01 > 0,
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+
+        // RAM bank 00-03
+        read_ram_bank(ram, self.ram_bank_no(), addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        if !self.ram_enable {
+            return false;
+        }
+
+        // RAM bank 00-03
+        write_ram_bank(ram, self.ram_bank_no(), addr, val)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            self.ram_enable as u8,
+            self.bank_no_upper,
+            self.bank_no_lower,
+            self.mode as u8,
+        ]
+    }
+
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        check_state_len(buf, 4)?;
+
+        self.ram_enable = buf[0] > 0;
+        self.bank_no_upper = buf[1];
+        self.bank_no_lower = buf[2];
+        self.mode = buf[3] > 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::io;
+
+use super::{check_state_len, read_rom_bank, Mapper};
+
+/// MBC2 (up to 256KB ROM and 512x4 bits of built-in RAM).
+pub struct Mbc2 {
+    ram_enable: bool,
+    bank_no: u8,
+}
+
+impl Mbc2 {
+    /// Creates a new `Mbc2`.
+    pub fn new() -> Self {
+        Mbc2 {
+            ram_enable: false,
+            bank_no: 0,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => rom[addr as usize],
+            // ROM bank 01-0f
+            _ => read_rom_bank(rom, self.bank_no.max(1) as usize, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM enable (address bit 8 clear) or ROM bank number (set)
+            0x0000..=0x3fff => {
+                if addr & 0x100 == 0 {
+                    self.ram_enable = val & 0x0f == 0x0a;
+                } else {
+                    self.bank_no = val & 0x0f;
+                }
+            }
+            0x4000..=0x7fff => (),
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+
+        // Built-in RAM (upper 4 bits are undefined and read as 1)
+        0xf0 | ram.get((addr & 0x1ff) as usize).cloned().unwrap_or(0xff)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        if !self.ram_enable {
+            return false;
+        }
+
+        // Built-in RAM (only the lower 4 bits are stored)
+        match ram.get_mut((addr & 0x1ff) as usize) {
+            Some(b) => {
+                *b = val & 0x0f;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.ram_enable as u8, self.bank_no]
+    }
+
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        check_state_len(buf, 2)?;
+
+        self.ram_enable = buf[0] > 0;
+        self.bank_no = buf[1];
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a ROM whose banks are filled with their bank number.
+    fn rom(banks: usize) -> Vec<u8> {
+        (0..banks * 16 * 1024)
+            .map(|i| (i / (16 * 1024)) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn register_select() {
+        let rom = rom(16);
+        let mut ram = vec![0; 512];
+        let mut mbc = Mbc2::new();
+
+        // Address bit 8 set selects the ROM bank number
+        mbc.write_register(0x2100, 0x0a);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x0a);
+        assert!(!mbc.write_ram(&mut ram, 0xa000, 0x05));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+
+        // Address bit 8 clear enables RAM, whatever the other bits
+        mbc.write_register(0x3e80, 0x0a);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x0a);
+        assert!(mbc.write_ram(&mut ram, 0xa000, 0x05));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xf5);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+    }
+
+    #[test]
+    fn rom_banks() {
+        let rom = rom(16);
+        let mut mbc = Mbc2::new();
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+
+        // Bank 0 maps to bank 1
+        mbc.write_register(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+
+        // Only the lower 4 bits select a bank
+        mbc.write_register(0x0100, 0xf3);
+        assert_eq!(mbc.read_rom(&rom, 0x7fff), 3);
+    }
+
+    #[test]
+    fn half_byte_ram() {
+        let mut ram = vec![0; 512];
+        let mut mbc = Mbc2::new();
+        mbc.write_register(0x0000, 0x0a);
+
+        // Only the lower 4 bits are stored, the upper ones read as 1
+        mbc.write_ram(&mut ram, 0xa123, 0xa5);
+        assert_eq!(ram[0x123], 0x05);
+        assert_eq!(mbc.read_ram(&ram, 0xa123), 0xf5);
+
+        // The 512 half-bytes are mirrored across 0xa000-0xbfff
+        assert_eq!(mbc.read_ram(&ram, 0xa323), 0xf5);
+        assert_eq!(mbc.read_ram(&ram, 0xbf23), 0xf5);
+        mbc.write_ram(&mut ram, 0xb1ff, 0x0c);
+        assert_eq!(ram[0x1ff], 0x0c);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut saved = Mbc2::new();
+        saved.write_register(0x0000, 0x0a);
+        saved.write_register(0x0100, 0x07);
+
+        let buf = saved.save_state();
+        let mut loaded = Mbc2::new();
+        loaded.load_state(&buf).unwrap();
+        assert_eq!(loaded.save_state(), buf);
+    }
+}
@@ -0,0 +1,256 @@
+use std::io;
+
+use super::rtc::{self, Rtc};
+use super::{check_state_len, read_ram_bank, read_rom_bank, write_ram_bank, Mapper};
+
+/// MBC3 (up to 2MB ROM, 32KB RAM and an optional real-time clock).
+pub struct Mbc3 {
+    ram_enable: bool,
+    /// ROM bank number
+    rom_bank_no: u8,
+    /// RAM bank number (0x00-0x03) or RTC register (0x08-0x0c)
+    ram_bank_no: u8,
+    /// Real-time clock
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    /// Creates a new `Mbc3`, optionally with a real-time clock.
+    pub fn new(has_rtc: bool) -> Self {
+        Mbc3 {
+            ram_enable: false,
+            rom_bank_no: 0,
+            ram_bank_no: 0,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => rom[addr as usize],
+            // ROM bank 01-7f
+            _ => read_rom_bank(rom, self.rom_bank_no.max(1) as usize, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM and timer enable
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            // ROM bank number
+            0x2000..=0x3fff => self.rom_bank_no = val & 0x7f,
+            // RAM bank number or RTC register select
+            0x4000..=0x5fff => self.ram_bank_no = val & 0x0f,
+            // Latch clock data
+            0x6000..=0x7fff => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.latch(val);
+                }
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+
+        match self.ram_bank_no {
+            // RAM bank 00-03
+            0x00..=0x03 => read_ram_bank(ram, self.ram_bank_no as usize, addr),
+            // RTC register
+            _ => match self.rtc {
+                Some(ref rtc) => rtc.read(self.ram_bank_no),
+                None => 0xff,
+            },
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        if !self.ram_enable {
+            return false;
+        }
+
+        match self.ram_bank_no {
+            // RAM bank 00-03
+            0x00..=0x03 => write_ram_bank(ram, self.ram_bank_no as usize, addr, val),
+            // RTC register
+            _ => match self.rtc {
+                Some(ref mut rtc) => {
+                    rtc.write(self.ram_bank_no, val);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn tick(&mut self, tick: u8) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.update(tick);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut buf = vec![self.ram_enable as u8, self.rom_bank_no, self.ram_bank_no];
+
+        if let Some(ref rtc) = self.rtc {
+            buf.extend(rtc.save_state());
+        }
+
+        buf
+    }
+
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let rtc_len = if self.rtc.is_some() {
+            Rtc::STATE_SIZE
+        } else {
+            0
+        };
+        check_state_len(buf, 3 + rtc_len)?;
+
+        self.ram_enable = buf[0] > 0;
+        self.rom_bank_no = buf[1];
+        self.ram_bank_no = buf[2];
+
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(&buf[3..]);
+        }
+
+        Ok(())
+    }
+
+    fn save_footer(&self) -> Vec<u8> {
+        match self.rtc {
+            Some(ref rtc) => rtc.save(),
+            None => Vec::new(),
+        }
+    }
+
+    fn footer_sizes(&self) -> &'static [usize] {
+        match self.rtc {
+            Some(_) => &[rtc::FOOTER_SIZE, rtc::FOOTER_SIZE_LEGACY],
+            None => &[],
+        }
+    }
+
+    fn load_footer(&mut self, buf: &[u8]) {
+        if let Some(ref mut rtc) = self.rtc {
+            if !buf.is_empty() {
+                rtc.load(buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a ROM whose banks are filled with their bank number.
+    fn rom(banks: usize) -> Vec<u8> {
+        (0..banks * 16 * 1024)
+            .map(|i| (i / (16 * 1024)) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn rom_banks() {
+        let rom = rom(128);
+        let mut mbc = Mbc3::new(false);
+
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+
+        // Bank 0 maps to bank 1
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+
+        // All 7 bits select a bank
+        mbc.write_register(0x2000, 0x7f);
+        assert_eq!(mbc.read_rom(&rom, 0x7fff), 0x7f);
+        mbc.write_register(0x3fff, 0xa5);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x25);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut ram = vec![0; 32 * 1024];
+        let mut mbc = Mbc3::new(false);
+
+        // RAM is disabled on reset
+        assert!(!mbc.write_ram(&mut ram, 0xa000, 0x12));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+        assert_eq!(ram[0], 0x00);
+
+        mbc.write_register(0x0000, 0x0a);
+        for bank in 0..4 {
+            mbc.write_register(0x4000, bank);
+            assert!(mbc.write_ram(&mut ram, 0xa001, 0x10 + bank));
+        }
+        for bank in 0..4 {
+            mbc.write_register(0x4000, bank);
+            assert_eq!(mbc.read_ram(&ram, 0xa001), 0x10 + bank);
+            assert_eq!(ram[bank as usize * 8 * 1024 + 1], 0x10 + bank);
+        }
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xa001), 0xff);
+    }
+
+    #[test]
+    fn rtc_registers() {
+        let mut ram = vec![0; 32 * 1024];
+        let mut mbc = Mbc3::new(true);
+        mbc.write_register(0x0000, 0x0a);
+
+        // Minutes
+        mbc.write_register(0x4000, 0x09);
+        assert!(mbc.write_ram(&mut ram, 0xa000, 42));
+        assert!(ram.iter().all(|&b| b == 0));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0);
+
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xbfff), 42);
+
+        // Without a clock, the registers read as 0xff
+        let mut mbc = Mbc3::new(false);
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x4000, 0x09);
+        assert!(!mbc.write_ram(&mut ram, 0xa000, 42));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+    }
+
+    #[test]
+    fn footer() {
+        let mut mbc = Mbc3::new(true);
+        assert_eq!(mbc.save_footer().len(), rtc::FOOTER_SIZE);
+        assert_eq!(mbc.footer_sizes(), &[48, 44]);
+        mbc.load_footer(&[]);
+
+        let mbc = Mbc3::new(false);
+        assert!(mbc.save_footer().is_empty());
+        assert!(mbc.footer_sizes().is_empty());
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut saved = Mbc3::new(true);
+        saved.write_register(0x0000, 0x0a);
+        saved.write_register(0x2000, 0x05);
+        saved.write_register(0x4000, 0x08);
+        saved.tick(200);
+
+        let buf = saved.save_state();
+        let mut loaded = Mbc3::new(true);
+        loaded.load_state(&buf).unwrap();
+        assert_eq!(loaded.save_state(), buf);
+
+        assert!(loaded.load_state(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::io;
+
+use super::{check_state_len, read_ram_bank, read_rom_bank, write_ram_bank, Mapper};
+
+/// MBC5 (up to 8MB ROM, 128KB RAM and an optional rumble motor).
+pub struct Mbc5 {
+    ram_enable: bool,
+    /// ROM bank number (9 bits)
+    rom_bank_no: u16,
+    /// RAM bank number
+    ram_bank_no: u8,
+    /// Whether the catridge has a rumble motor
+    has_rumble: bool,
+    /// Rumble motor state
+    rumble: bool,
+}
+
+impl Mbc5 {
+    /// Creates a new `Mbc5`, optionally with a rumble motor.
+    pub fn new(has_rumble: bool) -> Self {
+        Mbc5 {
+            ram_enable: false,
+            rom_bank_no: 1,
+            ram_bank_no: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => rom[addr as usize],
+            // ROM bank 000-1ff
+            _ => read_rom_bank(rom, self.rom_bank_no as usize, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM enable
+            0x0000..=0x1fff => self.ram_enable = val == 0x0a,
+            // ROM bank number (lower 8 bits)
+            0x2000..=0x2fff => self.rom_bank_no = (self.rom_bank_no & 0x100) | val as u16,
+            // ROM bank number (upper 1 bit)
+            0x3000..=0x3fff => {
+                self.rom_bank_no = (self.rom_bank_no & 0xff) | ((val as u16 & 0x01) << 8)
+            }
+            // RAM bank number (bit 3 drives the rumble motor if present)
+            0x4000..=0x5fff => {
+                if self.has_rumble {
+                    self.ram_bank_no = val & 0x07;
+                    self.rumble = val & 0x08 > 0;
+                } else {
+                    self.ram_bank_no = val & 0x0f;
+                }
+            }
+            0x6000..=0x7fff => (),
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+
+        // RAM bank 00-0f
+        read_ram_bank(ram, self.ram_bank_no as usize, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        if !self.ram_enable {
+            return false;
+        }
+
+        // RAM bank 00-0f
+        write_ram_bank(ram, self.ram_bank_no as usize, addr, val)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            self.ram_enable as u8,
+            self.rom_bank_no as u8,
+            (self.rom_bank_no >> 8) as u8,
+            self.ram_bank_no,
+            self.rumble as u8,
+        ]
+    }
+
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        check_state_len(buf, 5)?;
+
+        self.ram_enable = buf[0] > 0;
+        self.rom_bank_no = (buf[2] as u16 & 0x01) << 8 | buf[1] as u16;
+        self.ram_bank_no = buf[3];
+        self.rumble = buf[4] > 0;
+
+        Ok(())
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a ROM whose banks start with their 16-bit bank number.
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * 16 * 1024];
+        for (bank_no, bank) in rom.chunks_mut(16 * 1024).enumerate() {
+            bank[0] = bank_no as u8;
+            bank[1] = (bank_no >> 8) as u8;
+        }
+        rom
+    }
+
+    /// Returns the number of the ROM bank mapped at 0x4000-0x7fff.
+    fn mapped_bank(mbc: &Mbc5, rom: &[u8]) -> u16 {
+        mbc.read_rom(rom, 0x4000) as u16 | (mbc.read_rom(rom, 0x4001) as u16) << 8
+    }
+
+    #[test]
+    fn rom_banks() {
+        let rom = rom(512);
+        let mut mbc = Mbc5::new(false);
+        assert_eq!(mapped_bank(&mbc, &rom), 1);
+
+        mbc.write_register(0x2000, 0xff);
+        assert_eq!(mapped_bank(&mbc, &rom), 0x0ff);
+
+        // The 9th bit is written separately
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mapped_bank(&mbc, &rom), 0x1ff);
+        mbc.write_register(0x2fff, 0x23);
+        assert_eq!(mapped_bank(&mbc, &rom), 0x123);
+
+        // Only bit 0 of the upper register is used
+        mbc.write_register(0x3fff, 0xfe);
+        assert_eq!(mapped_bank(&mbc, &rom), 0x023);
+
+        // Unlike older MBCs, bank 0 can be mapped
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom), 0);
+    }
+
+    #[test]
+    fn rom_bank_wraps() {
+        let rom = rom(64);
+        let mut mbc = Mbc5::new(false);
+
+        mbc.write_register(0x2000, 0x45);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mapped_bank(&mbc, &rom), 0x05);
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut ram = vec![0; 128 * 1024];
+        let mut mbc = Mbc5::new(false);
+
+        // Only exactly 0x0a enables RAM
+        mbc.write_register(0x0000, 0x1a);
+        assert!(!mbc.write_ram(&mut ram, 0xa000, 0x12));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+        assert_eq!(ram[0], 0x00);
+
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x4000, 0x0f);
+        assert!(mbc.write_ram(&mut ram, 0xa000, 0x34));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0x34);
+        assert_eq!(ram[15 * 8 * 1024], 0x34);
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn missing_ram() {
+        let mut ram = vec![0; 8 * 1024];
+        let mut mbc = Mbc5::new(false);
+        mbc.write_register(0x0000, 0x0a);
+
+        assert!(mbc.write_ram(&mut ram, 0xbfff, 0x12));
+        mbc.write_register(0x4000, 0x01);
+        assert!(!mbc.write_ram(&mut ram, 0xa000, 0x12));
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+    }
+
+    #[test]
+    fn rumble() {
+        let mut ram = vec![0; 32 * 1024];
+        let mut mbc = Mbc5::new(true);
+        mbc.write_register(0x0000, 0x0a);
+
+        // Bit 3 drives the motor and isn't part of the RAM bank number
+        mbc.write_register(0x4000, 0x0b);
+        assert!(mbc.rumble());
+        mbc.write_ram(&mut ram, 0xa000, 0x56);
+        assert_eq!(ram[3 * 8 * 1024], 0x56);
+
+        mbc.write_register(0x4000, 0x03);
+        assert!(!mbc.rumble());
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0x56);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut saved = Mbc5::new(true);
+        saved.write_register(0x0000, 0x0a);
+        saved.write_register(0x2000, 0x34);
+        saved.write_register(0x3000, 0x01);
+        saved.write_register(0x4000, 0x0a);
+
+        let buf = saved.save_state();
+        let mut loaded = Mbc5::new(true);
+        loaded.load_state(&buf).unwrap();
+        assert_eq!(loaded.save_state(), buf);
+        assert!(loaded.rumble());
+    }
+}
@@ -0,0 +1,123 @@
+//! Memory bank controllers.
+
+use std::io;
+
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rom_only;
+mod rtc;
+
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::rom_only::RomOnly;
+
+/// A memory bank controller mapping catridge ROM and RAM into the memory
+/// space.
+pub trait Mapper {
+    /// Reads a byte from ROM (0x0000-0x7fff).
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8;
+
+    /// Writes a byte to a mapper register (0x0000-0x7fff).
+    fn write_register(&mut self, addr: u16, val: u8);
+
+    /// Reads a byte from external RAM (0xa000-0xbfff).
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8;
+
+    /// Writes a byte to external RAM (0xa000-0xbfff). Returns whether
+    /// battery-backed state was written, i.e. RAM is enabled and present.
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool;
+
+    /// Progresses the clock for a given number of ticks.
+    fn tick(&mut self, _tick: u8) {}
+
+    /// Serializes the mapper registers.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restores the mapper registers from `save_state` output.
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Returns extra battery-backed state appended to save files after the
+    /// RAM contents.
+    fn save_footer(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Returns the sizes of save file footers accepted by `load_footer`.
+    fn footer_sizes(&self) -> &'static [usize] {
+        &[]
+    }
+
+    /// Restores extra battery-backed state from the bytes following the RAM
+    /// contents in a save file.
+    fn load_footer(&mut self, _buf: &[u8]) {}
+
+    /// Returns whether the rumble motor is currently on.
+    fn rumble(&self) -> bool {
+        false
+    }
+}
+
+/// Creates the mapper for a given catridge type (header byte 0x0147), or
+/// returns `None` if the type is not supported.
+pub fn new_mapper(mbc_type: u8) -> Option<Box<dyn Mapper>> {
+    let mapper: Box<dyn Mapper> = match mbc_type {
+        0x00 | 0x08 | 0x09 => Box::new(RomOnly::new()),
+        0x01..=0x03 => Box::new(Mbc1::new()),
+        0x05 | 0x06 => Box::new(Mbc2::new()),
+        0x0f | 0x10 => Box::new(Mbc3::new(true)),
+        0x11..=0x13 => Box::new(Mbc3::new(false)),
+        0x19..=0x1b => Box::new(Mbc5::new(false)),
+        0x1c..=0x1e => Box::new(Mbc5::new(true)),
+        _ => return None,
+    };
+
+    Some(mapper)
+}
+
+/// Reads a byte from a 16KB ROM bank.
+fn read_rom_bank(rom: &[u8], bank_no: usize, addr: u16) -> u8 {
+    let offset = (16 * 1024) * bank_no + (addr & 0x3fff) as usize;
+
+    rom[offset % rom.len()]
+}
+
+/// Reads a byte from an 8KB RAM bank.
+fn read_ram_bank(ram: &[u8], bank_no: usize, addr: u16) -> u8 {
+    let offset = (8 * 1024) * bank_no + (addr & 0x1fff) as usize;
+
+    ram.get(offset).cloned().unwrap_or(0xff)
+}
+
+/// Writes a byte to an 8KB RAM bank. Returns whether the bank exists.
+fn write_ram_bank(ram: &mut [u8], bank_no: usize, addr: u16, val: u8) -> bool {
+    let offset = (8 * 1024) * bank_no + (addr & 0x1fff) as usize;
+
+    match ram.get_mut(offset) {
+        Some(b) => {
+            *b = val;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Checks the length of a serialized mapper state. Trailing bytes appended
+/// by newer versions are ignored.
+fn check_state_len(buf: &[u8], len: usize) -> io::Result<()> {
+    if buf.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Mapper state has {} bytes, expected at least {}",
+                buf.len(),
+                len
+            ),
+        ));
+    }
+
+    Ok(())
+}
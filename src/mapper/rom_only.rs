@@ -0,0 +1,37 @@
+use std::io;
+
+use super::{check_state_len, read_ram_bank, write_ram_bank, Mapper};
+
+/// Catridge without a memory bank controller (32KB ROM and up to 8KB RAM).
+pub struct RomOnly;
+
+impl RomOnly {
+    /// Creates a new `RomOnly`.
+    pub fn new() -> Self {
+        RomOnly
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        rom.get(addr as usize).cloned().unwrap_or(0xff)
+    }
+
+    fn write_register(&mut self, _addr: u16, _val: u8) {}
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        read_ram_bank(ram, 0, addr)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        write_ram_bank(ram, 0, addr, val)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        check_state_len(buf, 0)
+    }
+}
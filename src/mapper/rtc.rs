@@ -0,0 +1,399 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of clocks per second.
+const CLOCK_HZ: u32 = 4_194_304;
+
+/// Size of the RTC footer appended to save files (BGB/VBA-M format).
+pub const FOOTER_SIZE: usize = 48;
+/// Size of the legacy RTC footer with a 32-bit timestamp.
+pub const FOOTER_SIZE_LEGACY: usize = 44;
+
+/// Real-time clock of MBC3 catridges.
+pub struct Rtc {
+    /// Seconds, minutes, hours, day counter (lower 8 bits) and day counter
+    /// (upper 1 bit) with halt and carry flags
+    regs: [u8; 5],
+    /// Registers as seen by the CPU since the last latch
+    latched: [u8; 5],
+    /// Last value written to the latch register
+    latch_prev: u8,
+    /// Elapsed clocks in current second
+    counter: u32,
+}
+
+/// Returns the current UNIX time in seconds.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Rtc {
+    /// Size of the serialized clock state.
+    pub const STATE_SIZE: usize = 15;
+
+    /// Creates a new `Rtc`.
+    pub fn new() -> Self {
+        Rtc {
+            regs: [0; 5],
+            latched: [0; 5],
+            latch_prev: 0xff,
+            counter: 0,
+        }
+    }
+
+    /// Returns whether the clock is halted.
+    fn halted(&self) -> bool {
+        self.regs[4] & 0x40 > 0
+    }
+
+    /// Writes to the latch register. Writing 0x00 followed by 0x01 copies the
+    /// current time to the latched registers.
+    pub fn latch(&mut self, val: u8) {
+        if self.latch_prev == 0x00 && val == 0x01 {
+            self.latched = self.regs;
+        }
+
+        self.latch_prev = val;
+    }
+
+    /// Reads a latched RTC register (0x08-0x0c).
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08..=0x0c => self.latched[(reg - 0x08) as usize],
+            _ => 0xff,
+        }
+    }
+
+    /// Writes an RTC register (0x08-0x0c).
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            // Seconds (writing resets the sub-second counter)
+            0x08 => {
+                self.regs[0] = val & 0x3f;
+                self.counter = 0;
+            }
+            // Minutes
+            0x09 => self.regs[1] = val & 0x3f,
+            // Hours
+            0x0a => self.regs[2] = val & 0x1f,
+            // Day counter (lower 8 bits)
+            0x0b => self.regs[3] = val,
+            // Day counter (upper 1 bit), halt and carry
+            0x0c => self.regs[4] = val & 0xc1,
+            _ => (),
+        }
+    }
+
+    /// Advances the clock by one second.
+    fn tick_second(&mut self) {
+        // Out-of-range values wrap around at the register width without
+        // carrying into the next register.
+        self.regs[0] = (self.regs[0] + 1) & 0x3f;
+        if self.regs[0] != 60 {
+            return;
+        }
+        self.regs[0] = 0;
+
+        self.regs[1] = (self.regs[1] + 1) & 0x3f;
+        if self.regs[1] != 60 {
+            return;
+        }
+        self.regs[1] = 0;
+
+        self.regs[2] = (self.regs[2] + 1) & 0x1f;
+        if self.regs[2] != 24 {
+            return;
+        }
+        self.regs[2] = 0;
+
+        let (days, overflow) = self.regs[3].overflowing_add(1);
+        self.regs[3] = days;
+        if overflow {
+            if self.regs[4] & 0x01 > 0 {
+                // Day counter overflow
+                self.regs[4] = (self.regs[4] & !0x01) | 0x80;
+            } else {
+                self.regs[4] |= 0x01;
+            }
+        }
+    }
+
+    /// Advances the clock by a given number of seconds.
+    fn advance(&mut self, secs: u64) {
+        if self.halted() || secs == 0 {
+            return;
+        }
+
+        let days = (self.regs[4] as u64 & 0x01) << 8 | self.regs[3] as u64;
+        let total = secs
+            + self.regs[0] as u64
+            + self.regs[1] as u64 * 60
+            + self.regs[2] as u64 * 3600
+            + days * 86400;
+
+        let days = total / 86400;
+
+        self.regs[0] = (total % 60) as u8;
+        self.regs[1] = (total / 60 % 60) as u8;
+        self.regs[2] = (total / 3600 % 24) as u8;
+        self.regs[3] = days as u8;
+        self.regs[4] = (self.regs[4] & 0xc0) | ((days >> 8) & 0x01) as u8;
+
+        if days > 0x1ff {
+            self.regs[4] |= 0x80;
+        }
+    }
+
+    /// Serializes the clock state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::STATE_SIZE);
+
+        buf.extend_from_slice(&self.regs);
+        buf.extend_from_slice(&self.latched);
+        buf.push(self.latch_prev);
+        buf.extend_from_slice(&self.counter.to_le_bytes());
+
+        buf
+    }
+
+    /// Restores the clock state from `save_state` output.
+    pub fn load_state(&mut self, buf: &[u8]) {
+        let mut counter = [0; 4];
+
+        self.regs.copy_from_slice(&buf[0..5]);
+        self.latched.copy_from_slice(&buf[5..10]);
+        self.latch_prev = buf[10];
+        counter.copy_from_slice(&buf[11..15]);
+        self.counter = u32::from_le_bytes(counter);
+    }
+
+    /// Serializes the clock into a save file footer stamped with the
+    /// current time.
+    pub fn save(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE);
+
+        for reg in self.regs.iter().chain(self.latched.iter()) {
+            buf.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        buf.extend_from_slice(&unix_time().to_le_bytes());
+
+        buf
+    }
+
+    /// Restores the clock from a save file footer and advances it by the
+    /// time elapsed since the footer was written.
+    pub fn load(&mut self, buf: &[u8]) {
+        if buf.len() != FOOTER_SIZE && buf.len() != FOOTER_SIZE_LEGACY {
+            warn!("Ignoring RTC footer of invalid size {}", buf.len());
+            return;
+        }
+
+        let word = |i: usize| buf[i * 4];
+
+        for i in 0..5 {
+            self.regs[i] = word(i);
+            self.latched[i] = word(i + 5);
+        }
+
+        let mut stamp = [0; 8];
+        stamp[..buf.len() - 40].copy_from_slice(&buf[40..]);
+        let stamp = u64::from_le_bytes(stamp);
+
+        self.advance(unix_time().saturating_sub(stamp));
+    }
+
+    /// Progresses the clock for a given number of ticks.
+    pub fn update(&mut self, tick: u8) {
+        if self.halted() {
+            return;
+        }
+
+        self.counter += tick as u32;
+
+        if self.counter >= CLOCK_HZ {
+            self.counter -= CLOCK_HZ;
+            self.tick_second();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an `Rtc` with given registers, latched.
+    fn with_regs(regs: [u8; 5]) -> Rtc {
+        let mut rtc = Rtc::new();
+        rtc.regs = regs;
+        rtc.latch(0x00);
+        rtc.latch(0x01);
+        rtc
+    }
+
+    /// Reads the latched registers.
+    fn read_all(rtc: &Rtc) -> Vec<u8> {
+        (0x08..=0x0c).map(|reg| rtc.read(reg)).collect()
+    }
+
+    #[test]
+    fn latch_on_rising_edge() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x09, 12);
+
+        // Writing 0x01 without a preceding 0x00 doesn't latch
+        rtc.latch(0x01);
+        assert_eq!(rtc.read(0x09), 0);
+
+        rtc.latch(0x00);
+        assert_eq!(rtc.read(0x09), 0);
+        rtc.latch(0x01);
+        assert_eq!(rtc.read(0x09), 12);
+
+        // The latched registers don't follow the clock
+        rtc.write(0x09, 34);
+        assert_eq!(rtc.read(0x09), 12);
+        rtc.latch(0x01);
+        assert_eq!(rtc.read(0x09), 12);
+
+        // Any other value in between breaks the sequence
+        rtc.latch(0x00);
+        rtc.latch(0x02);
+        rtc.latch(0x01);
+        assert_eq!(rtc.read(0x09), 12);
+    }
+
+    #[test]
+    fn register_masks() {
+        let mut rtc = Rtc::new();
+        for reg in 0x08..=0x0c {
+            rtc.write(reg, 0xff);
+        }
+        rtc.latch(0x00);
+        rtc.latch(0x01);
+
+        assert_eq!(read_all(&rtc), vec![0x3f, 0x3f, 0x1f, 0xff, 0xc1]);
+        assert_eq!(rtc.read(0x0d), 0xff);
+    }
+
+    #[test]
+    fn tick_seconds() {
+        let mut rtc = with_regs([59, 59, 23, 0x00, 0x00]);
+
+        for _ in 0..CLOCK_HZ / 4 {
+            rtc.update(4);
+        }
+        rtc.latch(0x00);
+        rtc.latch(0x01);
+
+        assert_eq!(read_all(&rtc), vec![0, 0, 0, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn day_counter_carry() {
+        // Day 255 rolls over into the upper bit
+        let mut rtc = with_regs([59, 59, 23, 0xff, 0x00]);
+        rtc.tick_second();
+        assert_eq!(rtc.regs, [0, 0, 0, 0x00, 0x01]);
+
+        // Day 511 sets the carry flag and wraps to day 0
+        let mut rtc = with_regs([59, 59, 23, 0xff, 0x01]);
+        rtc.tick_second();
+        assert_eq!(rtc.regs, [0, 0, 0, 0x00, 0x80]);
+
+        // The carry flag stays set until it is cleared
+        rtc.tick_second();
+        assert_eq!(rtc.regs[4], 0x80);
+        rtc.write(0x0c, 0x00);
+        assert_eq!(rtc.regs[4], 0x00);
+    }
+
+    #[test]
+    fn halt() {
+        let mut rtc = with_regs([10, 0, 0, 0x00, 0x40]);
+
+        for _ in 0..CLOCK_HZ / 4 {
+            rtc.update(4);
+        }
+        assert_eq!(rtc.regs[0], 10);
+
+        rtc.advance(3600);
+        assert_eq!(rtc.regs, [10, 0, 0, 0x00, 0x40]);
+
+        // Clearing the halt flag resumes the clock
+        rtc.write(0x0c, 0x00);
+        for _ in 0..CLOCK_HZ / 4 {
+            rtc.update(4);
+        }
+        assert_eq!(rtc.regs[0], 11);
+    }
+
+    #[test]
+    fn advance() {
+        let mut rtc = with_regs([30, 0, 0, 0xff, 0x01]);
+
+        // One day and a bit past day 511
+        rtc.advance(86400 + 3661);
+        assert_eq!(rtc.regs, [31, 1, 1, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        // A halted clock isn't advanced by the time spent saving and loading
+        let mut saved = with_regs([1, 2, 3, 0x04, 0x41]);
+        saved.regs[0] = 5;
+
+        let buf = saved.save();
+        assert_eq!(buf.len(), FOOTER_SIZE);
+
+        let mut loaded = Rtc::new();
+        loaded.load(&buf);
+        assert_eq!(loaded.regs, saved.regs);
+        assert_eq!(loaded.latched, saved.latched);
+
+        // Legacy footers have a 32-bit timestamp
+        let mut loaded = Rtc::new();
+        loaded.load(&buf[..FOOTER_SIZE_LEGACY]);
+        assert_eq!(loaded.regs, saved.regs);
+        assert_eq!(loaded.latched, saved.latched);
+    }
+
+    #[test]
+    fn footer_elapsed_time() {
+        let saved = with_regs([0, 0, 0, 0x00, 0x00]);
+        let mut buf = saved.save();
+
+        // Saved 1 day, 1 hour, 1 minute and 1 second ago
+        let mut stamp = [0; 8];
+        stamp.copy_from_slice(&buf[40..]);
+        let stamp = u64::from_le_bytes(stamp) - 90061;
+        buf[40..].copy_from_slice(&stamp.to_le_bytes());
+
+        let mut loaded = Rtc::new();
+        loaded.load(&buf);
+        assert!(loaded.regs[0] == 1 || loaded.regs[0] == 2);
+        assert_eq!(&loaded.regs[1..], &[1, 1, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn footer_invalid_size() {
+        let mut rtc = with_regs([1, 2, 3, 4, 0]);
+        rtc.load(&[0; 40]);
+        assert_eq!(rtc.regs, [1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut saved = with_regs([1, 2, 3, 4, 0x01]);
+        saved.update(100);
+        saved.latch(0x00);
+
+        let buf = saved.save_state();
+        assert_eq!(buf.len(), Rtc::STATE_SIZE);
+
+        let mut loaded = Rtc::new();
+        loaded.load_state(&buf);
+        assert_eq!(loaded.save_state(), buf);
+    }
+}
@@ -1,7 +1,13 @@
+use std::io;
+
+use apu::APU;
+use bus::Bus;
 use catridge::Catridge;
 use io_device::IODevice;
 use joypad::Joypad;
 use ppu::PPU;
+use serial::Serial;
+use state::{Decoder, Encoder, StateReader, StateWriter};
 use timer::Timer;
 
 /// Memory space.
@@ -14,11 +20,15 @@ pub struct MMU {
     hram: [u8; 0x7f],
     /// Joypad
     pub joypad: Joypad,
+    /// Serial port
+    pub serial: Serial,
     /// Timer
     timer: Timer,
     // TODO should this be public?
     /// Pixel Processing Unit
     pub ppu: PPU,
+    /// Audio Processing Unit
+    pub apu: APU,
     /// Interrupt flag
     pub int_flag: u8,
     /// Interrupt enable
@@ -26,24 +36,66 @@ pub struct MMU {
 }
 
 impl MMU {
-    /// Creates a new `MMU`.
-    pub fn new(rom_name: &str) -> Self {
+    /// Creates a new `MMU` with a given catridge inserted.
+    pub fn new(catridge: Catridge) -> Self {
         MMU {
-            catridge: Catridge::new(rom_name),
+            catridge,
             ram: [0; 0x2000],
             hram: [0; 0x7f],
             joypad: Joypad::new(),
             ppu: PPU::new(),
+            apu: APU::new(),
+            serial: Serial::new(),
             timer: Timer::new(),
             int_flag: 0,
             int_enable: 0,
         }
     }
 
+    /// Writes the state of the memory and all devices to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        let mut enc = Encoder::new();
+        enc.bytes(&self.ram);
+        enc.bytes(&self.hram);
+        enc.u8(self.int_flag);
+        enc.u8(self.int_enable);
+
+        writer.chunk(b"MMU ", &enc.finish());
+        writer.chunk(b"PPU ", &self.ppu.save_state());
+        writer.chunk(b"APU ", &self.apu.save_state());
+        writer.chunk(b"TIMR", &self.timer.save_state());
+        writer.chunk(b"SER ", &self.serial.save_state());
+        writer.chunk(b"JOYP", &self.joypad.save_state());
+        writer.chunk(b"CART", &self.catridge.save_state());
+    }
+
+    /// Restores the state of the memory and all devices from a save state.
+    pub fn load_state(&mut self, reader: &StateReader) -> io::Result<()> {
+        let mut dec = Decoder::new(reader.chunk(b"MMU ")?);
+        dec.read_into(&mut self.ram)?;
+        dec.read_into(&mut self.hram)?;
+        self.int_flag = dec.u8()?;
+        self.int_enable = dec.u8()?;
+
+        self.ppu
+            .load_state(reader.chunk(b"PPU ")?, reader.minor())?;
+        // Save states before version 1.1 have no APU chunk
+        if let Some(buf) = reader.find(b"APU ") {
+            self.apu.load_state(buf)?;
+        }
+        self.timer.load_state(reader.chunk(b"TIMR")?)?;
+        // Save states before version 1.2 have no serial chunk
+        if let Some(buf) = reader.find(b"SER ") {
+            self.serial.load_state(buf)?;
+        }
+        self.joypad.load_state(reader.chunk(b"JOYP")?)?;
+        self.catridge.load_state(reader.chunk(b"CART")?)
+    }
+
     /// Starts a DMA transfer.
     // TODO OAM DMA Timing
     fn do_dma(&mut self, val: u8) {
-        if val < 0x80 || 0xdf < val {
+        if !(0x80..=0xdf).contains(&val) {
             panic!("Invalid DMA source address")
         }
 
@@ -73,10 +125,14 @@ impl MMU {
             0xfe00..=0xfe9f => self.ppu.write(addr, val),
             // Joypad
             0xff00 => self.joypad.write(addr, val),
+            // Serial
+            0xff01..=0xff02 => self.serial.write(addr, val),
             // Timer
             0xff04..=0xff07 => self.timer.write(addr, val),
             // Interrupt flag
             0xff0f => self.int_flag = val,
+            // APU
+            0xff10..=0xff3f => self.apu.write(addr, val),
             // PPU
             0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.write(addr, val),
             // OAM DMA
@@ -106,10 +162,14 @@ impl MMU {
             0xfe00..=0xfe9f => self.ppu.read(addr),
             // Joypad
             0xff00 => self.joypad.read(addr),
+            // Serial
+            0xff01..=0xff02 => self.serial.read(addr),
             // Timer
             0xff04..=0xff07 => self.timer.read(addr),
             // Interrupt flag
             0xff0f => self.int_flag,
+            // APU
+            0xff10..=0xff3f => self.apu.read(addr),
             // PPU
             0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.read(addr),
             // HRAM
@@ -124,7 +184,9 @@ impl MMU {
     pub fn update(&mut self, tick: u8) {
         self.catridge.update(tick);
         self.ppu.update(tick);
+        self.apu.update(tick);
         self.timer.update(tick);
+        self.serial.update(tick);
         self.joypad.update(tick);
 
         if self.ppu.irq_vblank {
@@ -142,9 +204,40 @@ impl MMU {
             self.timer.irq = false;
         }
 
+        if self.serial.irq {
+            self.int_flag |= 0x8;
+            self.serial.irq = false;
+        }
+
         if self.joypad.irq {
             self.int_flag |= 0x10;
             self.joypad.irq = false;
         }
     }
 }
+
+impl Bus for MMU {
+    fn read(&mut self, addr: u16) -> u8 {
+        MMU::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        MMU::write(self, addr, val)
+    }
+
+    fn tick(&mut self) {
+        self.update(4);
+    }
+
+    fn tick_cycles(&mut self, cycles: u8) {
+        self.update(cycles * 4);
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.int_flag & self.int_enable & 0x1f
+    }
+
+    fn ack_interrupt(&mut self, id: u8) {
+        self.int_flag &= !(1 << id);
+    }
+}
@@ -0,0 +1,199 @@
+//! Input movies.
+//!
+//! A movie records the joypad state of every frame together with the machine
+//! state it was started from, so that a run can be replayed bit-exactly.
+//! Optionally, a hash of the frame buffer is stored every few frames to detect
+//! when playback diverges from the recording.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use flate2::Crc;
+
+use gameboy::GameBoy;
+use state::{Decoder, Encoder};
+
+/// Magic number at the start of a movie file.
+const MAGIC: &[u8; 4] = b"GBRM";
+/// Format version.
+const VERSION: u16 = 1;
+
+/// Error returned when playback diverges from the recording.
+#[derive(Debug)]
+pub struct Desync {
+    /// First frame whose hash does not match
+    pub frame: usize,
+    /// Frame buffer hash in the movie
+    pub expected: u32,
+    /// Frame buffer hash during playback
+    pub actual: u32,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Playback desynced at frame {} (hash 0x{:08x}, expected 0x{:08x})",
+            self.frame, self.actual, self.expected
+        )
+    }
+}
+
+impl error::Error for Desync {}
+
+/// Computes the hash of the current frame buffer.
+fn frame_hash(gameboy: &GameBoy) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(gameboy.frame_buffer());
+    crc.sum()
+}
+
+/// A recorded sequence of joypad inputs.
+pub struct Movie {
+    /// Save state the movie starts from
+    start_state: Vec<u8>,
+    /// Number of frames between two frame buffer hashes (0 to disable)
+    hash_interval: u32,
+    /// Keypress state of each frame
+    inputs: Vec<u8>,
+    /// Frame buffer hashes
+    hashes: Vec<u32>,
+}
+
+impl Movie {
+    /// Creates a new empty `Movie` starting from the current state of a
+    /// given machine. A frame buffer hash is recorded every `hash_interval`
+    /// frames unless it is 0.
+    pub fn new(gameboy: &GameBoy, hash_interval: u32) -> Self {
+        Movie {
+            start_state: gameboy.save_state(),
+            hash_interval,
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Returns the number of recorded frames.
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Returns whether no frames have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Returns whether a frame buffer hash is stored after a given frame.
+    fn has_hash(&self, frame: usize) -> bool {
+        self.hash_interval > 0
+            && frame % self.hash_interval as usize == self.hash_interval as usize - 1
+    }
+
+    /// Restores the state the movie starts from.
+    pub fn rewind(&self, gameboy: &mut GameBoy) -> io::Result<()> {
+        gameboy.load_state(&self.start_state)
+    }
+
+    /// Emulates one frame and records its input.
+    pub fn record_frame(&mut self, gameboy: &mut GameBoy) {
+        let frame = self.inputs.len();
+
+        self.inputs.push(gameboy.key_state());
+        gameboy.run_frame();
+
+        if self.has_hash(frame) {
+            self.hashes.push(frame_hash(gameboy));
+        }
+    }
+
+    /// Emulates a given frame with its recorded input. Frames must be played
+    /// in order after calling `rewind`.
+    pub fn play_frame(&self, frame: usize, gameboy: &mut GameBoy) -> Result<(), Desync> {
+        gameboy.set_key_state(self.inputs[frame]);
+        gameboy.run_frame();
+
+        if self.has_hash(frame) {
+            let idx = (frame + 1) / self.hash_interval as usize - 1;
+
+            if let Some(&expected) = self.hashes.get(idx) {
+                let actual = frame_hash(gameboy);
+
+                if actual != expected {
+                    return Err(Desync {
+                        frame,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the movie.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.bytes(MAGIC);
+        enc.u16(VERSION);
+        enc.blob(&self.start_state);
+        enc.u32(self.hash_interval);
+        enc.blob(&self.inputs);
+        enc.u32(self.hashes.len() as u32);
+        for &hash in &self.hashes {
+            enc.u32(hash);
+        }
+        enc.finish()
+    }
+
+    /// Deserializes a movie.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let mut dec = Decoder::new(buf);
+
+        if dec.bytes(4)? != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a movie file",
+            ));
+        }
+
+        let version = dec.u16()?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported movie version {}", version),
+            ));
+        }
+
+        let start_state = dec.blob()?.to_vec();
+        let hash_interval = dec.u32()?;
+        let inputs = dec.blob()?.to_vec();
+        let mut hashes = Vec::new();
+        for _ in 0..dec.u32()? {
+            hashes.push(dec.u32()?);
+        }
+
+        Ok(Movie {
+            start_state,
+            hash_interval,
+            inputs,
+            hashes,
+        })
+    }
+
+    /// Writes the movie to a file.
+    pub fn save(&self, fname: &str) -> io::Result<()> {
+        info!("Writing movie to: {}", fname);
+
+        fs::write(fname, self.to_bytes())
+    }
+
+    /// Reads a movie from a file.
+    pub fn load(fname: &str) -> io::Result<Self> {
+        info!("Reading movie from: {}", fname);
+
+        Self::from_bytes(&fs::read(fname)?)
+    }
+}
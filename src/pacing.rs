@@ -0,0 +1,138 @@
+//! Frame pacing.
+//!
+//! Keeps emulation in sync with wall-clock time at the native refresh rate
+//! of 4194304 / 70224 ≈ 59.73 Hz. Frame deadlines are computed from a fixed
+//! origin rather than from the previous frame, so sleep inaccuracies do not
+//! accumulate into drift.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gameboy::FRAME_TICKS;
+
+/// CPU clock frequency in Hz.
+const CLOCK_HZ: f64 = 4_194_304.0;
+/// Native refresh rate in Hz.
+pub const FRAME_RATE: f64 = CLOCK_HZ / FRAME_TICKS as f64;
+/// Speed multipliers selectable with `faster` and `slower`.
+pub const SPEEDS: [f64; 7] = [0.25, 0.5, 1.0, 1.5, 2.0, 3.0, 4.0];
+/// Index of the normal speed in `SPEEDS`.
+const NORMAL_SPEED: usize = 2;
+/// Maximum number of consecutive frames that are not rendered when the host
+/// cannot keep up.
+const MAX_FRAME_SKIP: u32 = 4;
+/// Lag after which the pacer stops catching up and starts over.
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+/// Paces emulated frames against a monotonic clock.
+pub struct Pacer {
+    /// Index of the current speed in `SPEEDS`
+    speed: usize,
+    /// Whether the speed limit is disabled (fast-forward)
+    uncapped: bool,
+    /// Time the frame count is measured from
+    origin: Instant,
+    /// Number of frames emulated since `origin`
+    frames: u32,
+    /// Number of consecutive frames that were not rendered
+    skipped: u32,
+    /// Time the last frame was rendered
+    last_render: Instant,
+}
+
+impl Pacer {
+    /// Creates a new `Pacer` running at normal speed.
+    pub fn new() -> Self {
+        let now = Instant::now();
+
+        Pacer {
+            speed: NORMAL_SPEED,
+            uncapped: false,
+            origin: now,
+            frames: 0,
+            skipped: 0,
+            last_render: now,
+        }
+    }
+
+    /// Returns the current speed multiplier, or `None` if uncapped.
+    pub fn speed(&self) -> Option<f64> {
+        if self.uncapped {
+            None
+        } else {
+            Some(SPEEDS[self.speed])
+        }
+    }
+
+    /// Switches to the next higher speed multiplier.
+    pub fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+        self.restart();
+    }
+
+    /// Switches to the next lower speed multiplier.
+    pub fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+        self.restart();
+    }
+
+    /// Switches back to normal speed.
+    pub fn reset_speed(&mut self) {
+        self.speed = NORMAL_SPEED;
+        self.restart();
+    }
+
+    /// Enables or disables the speed limit.
+    pub fn set_uncapped(&mut self, uncapped: bool) {
+        if self.uncapped != uncapped {
+            self.uncapped = uncapped;
+            self.restart();
+        }
+    }
+
+    /// Measures subsequent frames from now on.
+    fn restart(&mut self) {
+        self.origin = Instant::now();
+        self.frames = 0;
+    }
+
+    /// Returns the duration of one frame at the current speed.
+    fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / (FRAME_RATE * SPEEDS[self.speed]))
+    }
+
+    /// Should be called once per emulated frame. Waits until the next frame
+    /// is due and returns whether it should be rendered, which is not the
+    /// case when the host is falling behind or running uncapped faster than
+    /// the display.
+    pub fn end_frame(&mut self) -> bool {
+        let now = Instant::now();
+
+        if self.uncapped {
+            // Render at the native refresh rate only
+            let render = now - self.last_render >= Duration::from_secs_f64(1.0 / FRAME_RATE);
+            if render {
+                self.last_render = now;
+            }
+            return render;
+        }
+
+        self.frames += 1;
+        let deadline = self.origin + self.frame_duration() * self.frames;
+
+        if now < deadline {
+            thread::sleep(deadline - now);
+        } else if now - deadline > MAX_LAG {
+            // Too far behind to catch up
+            self.restart();
+        } else if now - deadline > self.frame_duration() && self.skipped < MAX_FRAME_SKIP {
+            self.skipped += 1;
+            return false;
+        }
+
+        self.skipped = 0;
+        self.last_render = now;
+
+        true
+    }
+}
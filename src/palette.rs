@@ -0,0 +1,171 @@
+//! Colour palettes that map the four DMG shades to RGB.
+//!
+//! User palettes are read from a config file (by default
+//! `$XDG_CONFIG_HOME/gbr/palettes.conf` or `~/.config/gbr/palettes.conf`)
+//! with one palette per line, giving the colours from lightest to darkest:
+//!
+//! ```text
+//! # Comment
+//! autumn = #fff6d3 #f9a875 #eb6b6f #7c3f58
+//! ```
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// A palette of four RGB colours, from lightest to darkest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    /// Name of the palette
+    pub name: String,
+    /// RGB colours of the four shades
+    pub colors: [[u8; 3]; 4],
+}
+
+/// Built-in palettes.
+const PRESETS: [(&str, [[u8; 3]; 4]); 4] = [
+    (
+        "grey",
+        [
+            [0xff, 0xff, 0xff],
+            [0xaa, 0xaa, 0xaa],
+            [0x55, 0x55, 0x55],
+            [0x00, 0x00, 0x00],
+        ],
+    ),
+    (
+        "dmg",
+        [
+            [0x9b, 0xbc, 0x0f],
+            [0x8b, 0xac, 0x0f],
+            [0x30, 0x62, 0x30],
+            [0x0f, 0x38, 0x0f],
+        ],
+    ),
+    (
+        "pocket",
+        [
+            [0xc4, 0xcf, 0xa1],
+            [0x8b, 0x95, 0x6d],
+            [0x4d, 0x53, 0x3c],
+            [0x1f, 0x1f, 0x1f],
+        ],
+    ),
+    (
+        "light",
+        [
+            [0x00, 0xb5, 0x81],
+            [0x00, 0x9a, 0x71],
+            [0x00, 0x69, 0x4a],
+            [0x00, 0x4f, 0x3b],
+        ],
+    ),
+];
+
+impl Palette {
+    /// Returns the built-in palettes: grey (default), pea-green DMG, Pocket
+    /// and Light.
+    pub fn presets() -> Vec<Palette> {
+        PRESETS
+            .iter()
+            .map(|&(name, colors)| Palette {
+                name: String::from(name),
+                colors,
+            })
+            .collect()
+    }
+
+    /// Parses a colour given as `#rrggbb` or `rrggbb`.
+    fn parse_color(s: &str) -> Option<[u8; 3]> {
+        let s = s.trim_start_matches('#');
+        if s.len() != 6 {
+            return None;
+        }
+
+        let rgb = u32::from_str_radix(s, 16).ok()?;
+
+        Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+    }
+
+    /// Parses palettes in the config file format.
+    pub fn parse_config(config: &str) -> Result<Vec<Palette>, String> {
+        let mut palettes = Vec::new();
+
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = || format!("Invalid palette at line {}: {}", i + 1, line);
+            let mut it = line.splitn(2, '=');
+            let name = it.next().ok_or_else(err)?.trim();
+            let colors = it
+                .next()
+                .ok_or_else(err)?
+                .split_whitespace()
+                .map(Self::parse_color)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(err)?;
+
+            if name.is_empty() || colors.len() != 4 {
+                return Err(err());
+            }
+
+            palettes.push(Palette {
+                name: String::from(name),
+                colors: [colors[0], colors[1], colors[2], colors[3]],
+            });
+        }
+
+        Ok(palettes)
+    }
+
+    /// Reads palettes from a config file.
+    pub fn load_config(fname: &str) -> io::Result<Vec<Palette>> {
+        let config = fs::read_to_string(fname)?;
+
+        Self::parse_config(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the path of the default config file.
+    pub fn default_config_path() -> Option<PathBuf> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(config_dir.join("gbr").join("palettes.conf"))
+    }
+
+    /// Returns the built-in palettes followed by those from a given config
+    /// file, or from the default config file if it exists.
+    pub fn load_all(config: Option<&str>) -> io::Result<Vec<Palette>> {
+        let mut palettes = Self::presets();
+
+        let fname = match config {
+            Some(fname) => Some(PathBuf::from(fname)),
+            None => Self::default_config_path().filter(|path| path.exists()),
+        };
+
+        if let Some(fname) = fname {
+            palettes.extend(Self::load_config(&fname.to_string_lossy())?);
+        }
+
+        Ok(palettes)
+    }
+
+    /// Converts shades to RGB24.
+    pub fn apply(&self, shades: &[u8]) -> Vec<u8> {
+        shades
+            .iter()
+            .flat_map(|&shade| self.colors[(shade & 0x3) as usize].iter().cloned())
+            .collect()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::presets().remove(0)
+    }
+}
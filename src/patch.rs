@@ -0,0 +1,520 @@
+//! IPS, UPS and BPS soft patches.
+
+use flate2::Crc;
+
+use catridge::CartridgeError;
+use header::CartridgeHeader;
+
+/// Largest ROM a patch may produce (MBC5 supports up to 8 MiB).
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+/// Returns a patch error with a given message.
+fn err<T>(msg: &str) -> Result<T, CartridgeError> {
+    Err(CartridgeError::Patch(String::from(msg)))
+}
+
+/// Adds two offsets, failing if the result overflows.
+fn add(a: usize, b: usize) -> Result<usize, CartridgeError> {
+    match a.checked_add(b) {
+        Some(sum) => Ok(sum),
+        None => err("Patch offset is out of range"),
+    }
+}
+
+/// Computes the CRC32 of a buffer.
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(buf);
+    crc.sum()
+}
+
+/// Reads a little-endian 32-bit integer.
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+/// Cursor over patch data.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> Self {
+        Reader { buf, pos }
+    }
+
+    /// Reads a byte.
+    fn u8(&mut self) -> Result<u8, CartridgeError> {
+        match self.buf.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => err("Unexpected end of patch"),
+        }
+    }
+
+    /// Reads a given number of bytes.
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CartridgeError> {
+        let end = add(self.pos, len)?;
+        if end > self.buf.len() {
+            return err("Unexpected end of patch");
+        }
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    /// Reads a big-endian integer of a given number of bytes.
+    fn be(&mut self, len: usize) -> Result<usize, CartridgeError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    /// Reads a variable-length integer as used by UPS and BPS.
+    fn varint(&mut self) -> Result<usize, CartridgeError> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+
+        loop {
+            let x = self.u8()?;
+            data = data.wrapping_add((x & 0x7f) as usize * shift);
+
+            if x & 0x80 > 0 {
+                return Ok(data);
+            }
+
+            shift <<= 7;
+            data = data.wrapping_add(shift);
+        }
+    }
+}
+
+/// Applies an IPS patch.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut out = rom.to_vec();
+    let mut r = Reader::new(patch, 5);
+
+    loop {
+        let tag = r.bytes(3)?;
+        if tag == b"EOF" {
+            break;
+        }
+
+        let offset = (tag[0] as usize) << 16 | (tag[1] as usize) << 8 | tag[2] as usize;
+        let size = r.be(2)?;
+
+        let (size, data) = if size == 0 {
+            // RLE record
+            let size = r.be(2)?;
+            let val = r.u8()?;
+            (size, vec![val; size])
+        } else {
+            (size, r.bytes(size)?.to_vec())
+        };
+
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+
+    // Optional truncation extension
+    if let Ok(len) = r.be(3) {
+        out.truncate(len);
+    }
+
+    Ok(out)
+}
+
+/// Applies a UPS patch.
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    if patch.len() < 16 {
+        return err("UPS patch is too short");
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    if crc32(&patch[..patch.len() - 4]) != read_u32(&footer[8..]) {
+        return err("UPS patch checksum mismatch");
+    }
+    if crc32(rom) != read_u32(&footer[0..]) {
+        return err("UPS patch does not match this ROM");
+    }
+
+    let mut r = Reader::new(&patch[..patch.len() - 12], 4);
+    let input_size = r.varint()?;
+    let output_size = r.varint()?;
+
+    if input_size != rom.len() {
+        return err("UPS patch does not match this ROM");
+    }
+    if output_size > MAX_ROM_SIZE {
+        return err("UPS patch produces a ROM that is too large");
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(output_size, 0);
+
+    let mut pos = 0;
+    while r.pos < r.buf.len() {
+        pos = add(pos, r.varint()?)?;
+
+        loop {
+            let x = r.u8()?;
+
+            if pos < out.len() {
+                out[pos] ^= x;
+            }
+            pos = add(pos, 1)?;
+
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32(&out) != read_u32(&footer[4..]) {
+        return err("UPS patch produced an invalid ROM");
+    }
+
+    Ok(out)
+}
+
+/// Applies a BPS relative offset (sign in bit 0, magnitude in the remaining
+/// bits) to an offset.
+fn relative_offset(offset: isize, d: usize) -> Result<isize, CartridgeError> {
+    let delta = (d >> 1) as isize;
+    let offset = if d & 1 > 0 {
+        offset.checked_sub(delta)
+    } else {
+        offset.checked_add(delta)
+    };
+
+    match offset {
+        Some(offset) => Ok(offset),
+        None => err("Patch offset is out of range"),
+    }
+}
+
+/// Applies a BPS patch.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    if patch.len() < 16 {
+        return err("BPS patch is too short");
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    if crc32(&patch[..patch.len() - 4]) != read_u32(&footer[8..]) {
+        return err("BPS patch checksum mismatch");
+    }
+    if crc32(rom) != read_u32(&footer[0..]) {
+        return err("BPS patch does not match this ROM");
+    }
+
+    let mut r = Reader::new(&patch[..patch.len() - 12], 4);
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata_size = r.varint()?;
+    r.bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return err("BPS patch does not match this ROM");
+    }
+    if target_size > MAX_ROM_SIZE {
+        return err("BPS patch produces a ROM that is too large");
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    while r.pos < r.buf.len() {
+        let data = r.varint()?;
+        let len = (data >> 2) + 1;
+        if add(out.len(), len)? > target_size {
+            return err("BPS patch writes beyond the target");
+        }
+
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                if add(start, len)? > rom.len() {
+                    return err("BPS patch reads beyond the source");
+                }
+                out.extend_from_slice(&rom[start..start + len]);
+            }
+            // TargetRead
+            1 => out.extend_from_slice(r.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_offset = relative_offset(source_offset, r.varint()?)?;
+
+                if source_offset < 0 || add(source_offset as usize, len)? > rom.len() {
+                    return err("BPS patch reads beyond the source");
+                }
+                let start = source_offset as usize;
+                out.extend_from_slice(&rom[start..start + len]);
+                source_offset += len as isize;
+            }
+            // TargetCopy (source and destination may overlap)
+            _ => {
+                target_offset = relative_offset(target_offset, r.varint()?)?;
+
+                for _ in 0..len {
+                    if target_offset < 0 || target_offset as usize >= out.len() {
+                        return err("BPS patch reads beyond the target");
+                    }
+                    let b = out[target_offset as usize];
+                    out.push(b);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size || crc32(&out) != read_u32(&footer[4..]) {
+        return err("BPS patch produced an invalid ROM");
+    }
+
+    Ok(out)
+}
+
+/// Applies an IPS, UPS or BPS patch to a ROM image. The format is detected
+/// from the patch header. UPS and BPS checksums are verified.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        err("Unknown patch format")
+    }
+}
+
+/// Recomputes the header and global checksums of a ROM image.
+pub fn fix_checksums(rom: &mut [u8]) {
+    if rom.len() < 0x0150 {
+        return;
+    }
+
+    rom[0x014d] = CartridgeHeader::compute_header_checksum(rom);
+
+    let global_chksum = CartridgeHeader::compute_global_checksum(rom);
+    rom[0x014e] = (global_chksum >> 8) as u8;
+    rom[0x014f] = global_chksum as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a variable-length integer as used by UPS and BPS.
+    fn varint(mut data: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        loop {
+            let x = (data & 0x7f) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn le32(val: u32) -> Vec<u8> {
+        vec![
+            val as u8,
+            (val >> 8) as u8,
+            (val >> 16) as u8,
+            (val >> 24) as u8,
+        ]
+    }
+
+    /// Appends the source, target and patch CRC32s to a UPS or BPS patch.
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(le32(crc32(source)));
+        patch.extend(le32(crc32(target)));
+        let crc = crc32(&patch);
+        patch.extend(le32(crc));
+        patch
+    }
+
+    fn ips_patch() -> Vec<u8> {
+        let mut patch = b"PATCH".to_vec();
+        // Offset 2, 3 bytes
+        patch.extend(&[0x00, 0x00, 0x02, 0x00, 0x03, 0x11, 0x22, 0x33]);
+        // Offset 8, RLE of 4 bytes
+        patch.extend(&[0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x04, 0xaa]);
+        // Offset 14, 4 bytes beyond the end of the ROM
+        patch.extend(&[0x00, 0x00, 0x0e, 0x00, 0x04, 0x01, 0x02, 0x03, 0x04]);
+        patch.extend(b"EOF");
+        patch
+    }
+
+    #[test]
+    fn ips() {
+        let rom = vec![0; 16];
+        let out = apply(&rom, &ips_patch()).unwrap();
+
+        assert_eq!(
+            out,
+            vec![
+                0x00, 0x00, 0x11, 0x22, 0x33, 0x00, 0x00, 0x00, 0xaa, 0xaa, 0xaa, 0xaa, 0x00, 0x00,
+                0x01, 0x02, 0x03, 0x04
+            ]
+        );
+    }
+
+    #[test]
+    fn ips_truncate() {
+        let mut patch = ips_patch();
+        patch.extend(&[0x00, 0x00, 0x06]);
+
+        let out = apply(&[0; 16], &patch).unwrap();
+        assert_eq!(out, vec![0x00, 0x00, 0x11, 0x22, 0x33, 0x00]);
+    }
+
+    #[test]
+    fn ips_truncated_record() {
+        let mut patch = ips_patch();
+        patch.truncate(patch.len() - 5);
+
+        assert!(apply(&[0; 16], &patch).is_err());
+    }
+
+    /// Builds a UPS patch from the given hunks of (skip, XOR bytes).
+    fn ups_patch(source: &[u8], target: &[u8], hunks: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        for &(skip, xor) in hunks {
+            patch.extend(varint(skip));
+            patch.extend(xor);
+            patch.push(0);
+        }
+        finish(patch, source, target)
+    }
+
+    #[test]
+    fn ups() {
+        let source = b"ABCDEFGH";
+        let target = b"ABxDEFGHIJ";
+        let patch = ups_patch(source, target, &[(2, &[b'C' ^ b'x']), (4, b"IJ")]);
+
+        assert_eq!(apply(source, &patch).unwrap(), target.to_vec());
+    }
+
+    #[test]
+    fn ups_wrong_rom() {
+        let patch = ups_patch(b"ABCDEFGH", b"ABxDEFGH", &[(2, &[b'C' ^ b'x'])]);
+
+        assert!(apply(b"ABCDEFGX", &patch).is_err());
+    }
+
+    #[test]
+    fn ups_bad_checksum() {
+        let mut patch = ups_patch(b"ABCDEFGH", b"ABxDEFGH", &[(2, &[b'C' ^ b'x'])]);
+        patch[6] ^= 1;
+
+        assert!(apply(b"ABCDEFGH", &patch).is_err());
+    }
+
+    #[test]
+    fn ups_bad_output_checksum() {
+        let source = b"ABCDEFGH";
+        let mut patch = ups_patch(source, b"ABxDEFGH", &[(2, &[b'C' ^ b'x'])]);
+        let len = patch.len();
+        patch[len - 8] ^= 1;
+        let crc = crc32(&patch[..len - 4]);
+        patch.truncate(len - 4);
+        patch.extend(le32(crc));
+
+        assert!(apply(source, &patch).is_err());
+    }
+
+    #[test]
+    fn ups_offset_overflow() {
+        let source = b"ABCDEFGH";
+        let patch = ups_patch(source, source, &[(usize::MAX, &[]), (1, &[])]);
+
+        assert!(apply(source, &patch).is_err());
+    }
+
+    /// Builds a BPS patch from encoded actions.
+    fn bps_patch(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(0));
+        patch.extend(actions);
+        finish(patch, source, target)
+    }
+
+    /// Encodes a BPS action of a given kind and length.
+    fn action(kind: usize, len: usize) -> Vec<u8> {
+        varint((len - 1) << 2 | kind)
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"ABCDEFGH";
+        let target = b"ABCDxyGHABC";
+
+        let mut actions = Vec::new();
+        // SourceRead "ABCD"
+        actions.extend(action(0, 4));
+        // TargetRead "xy"
+        actions.extend(action(1, 2));
+        actions.extend(b"xy");
+        // SourceCopy "GH" from offset 6
+        actions.extend(action(2, 2));
+        actions.extend(varint(6 << 1));
+        // TargetCopy "ABC" from offset 0
+        actions.extend(action(3, 3));
+        actions.extend(varint(0));
+
+        let patch = bps_patch(source, target, &actions);
+        assert_eq!(apply(source, &patch).unwrap(), target.to_vec());
+    }
+
+    #[test]
+    fn bps_wrong_rom() {
+        let patch = bps_patch(b"ABCDEFGH", b"ABCD", &action(0, 4));
+
+        assert!(apply(b"ABCDEFGX", &patch).is_err());
+    }
+
+    #[test]
+    fn bps_bad_checksum() {
+        let mut patch = bps_patch(b"ABCDEFGH", b"ABCD", &action(0, 4));
+        patch[5] ^= 1;
+
+        assert!(apply(b"ABCDEFGH", &patch).is_err());
+    }
+
+    #[test]
+    fn bps_copy_out_of_range() {
+        let source = b"ABCDEFGH";
+        let mut actions = action(2, 4);
+        // Backwards from offset 0
+        actions.extend(varint(1 << 1 | 1));
+
+        let patch = bps_patch(source, b"ABCD", &actions);
+        assert!(apply(source, &patch).is_err());
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(apply(&[0; 16], b"NOTAPATCH").is_err());
+    }
+}
@@ -1,9 +1,12 @@
+use std::io;
+
 use io_device::IODevice;
+use state::{Decoder, Encoder};
 
 /// Width of screen in pixels.
-const SCREEN_W: u8 = 160;
+pub const SCREEN_W: u8 = 160;
 /// Height of screen in pixels.
-const SCREEN_H: u8 = 144;
+pub const SCREEN_H: u8 = 144;
 
 #[derive(Copy, Clone, PartialEq)]
 enum BGPriority {
@@ -97,7 +100,7 @@ impl PPU {
             (tile_no as u16) << 4
         } else {
             // Use tile set #2 (0x0800-0x0fff) and #3 (0x1000-0x17ff)
-            (0x1000 as u16).wrapping_add(((tile_no as i8 as i16) << 4) as u16)
+            0x1000_u16.wrapping_add(((tile_no as i8 as i16) << 4) as u16)
         };
         let row_addr = tile_data_addr + (offset_y << 1) as u16;
 
@@ -138,14 +141,10 @@ impl PPU {
         self.fetch_bg_window_tile(tile_x, tile_y, offset_y, tile_map_base)
     }
 
-    /// Converts color number to brightness using palette.
+    /// Converts color number to shade (0: lightest, 3: darkest) using
+    /// palette.
     fn map_color(&self, color_no: u8, palette: u8) -> u8 {
-        match (palette >> (color_no << 1)) & 0x3 {
-            0 => 0xff,
-            1 => 0xaa,
-            2 => 0x55,
-            3 | _ => 0x00,
-        }
+        (palette >> (color_no << 1)) & 0x3
     }
 
     /// Returns the color number at a given position from tile data.
@@ -172,15 +171,13 @@ impl PPU {
 
         for x in 0..SCREEN_W {
             // Check if window is enabled
-            if self.lcdc & 0x20 > 0 {
-                if self.wy <= self.ly && self.wx == x + 7 {
-                    tile_x = 0;
-                    tile_y = (self.ly - self.wy) >> 3;
-                    offset_x = 0;
-                    offset_y = (self.ly - self.wy) & 0x7;
-                    tile = self.fetch_window_tile(tile_x, tile_y, offset_y);
-                    window = true;
-                }
+            if self.lcdc & 0x20 > 0 && self.wy <= self.ly && self.wx == x + 7 {
+                tile_x = 0;
+                tile_y = (self.ly - self.wy) >> 3;
+                offset_x = 0;
+                offset_y = (self.ly - self.wy) & 0x7;
+                tile = self.fetch_window_tile(tile_x, tile_y, offset_y);
+                window = true;
             }
 
             let color_no = self.get_color_no(tile, 7 - offset_x);
@@ -311,11 +308,73 @@ impl PPU {
         }
     }
 
-    /// Returns the current contents of the frame buffer.
+    /// Returns the current contents of the frame buffer as shades from 0
+    /// (lightest) to 3 (darkest).
     pub fn frame_buffer(&self) -> &[u8] {
         &self.frame_buffer
     }
 
+    /// Serializes the PPU state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.bytes(&self.vram);
+        enc.bytes(&self.oam);
+        for &reg in &[
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.dma, self.bgp,
+            self.obp0, self.obp1, self.wy, self.wx,
+        ] {
+            enc.u8(reg);
+        }
+        enc.bool(self.irq_vblank);
+        enc.bool(self.irq_lcdc);
+        enc.u16(self.counter);
+        enc.bytes(&self.frame_buffer);
+        enc.finish()
+    }
+
+    /// Restores the PPU state written by a given minor format version.
+    pub fn load_state(&mut self, buf: &[u8], minor: u16) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+        dec.read_into(&mut self.vram)?;
+        dec.read_into(&mut self.oam)?;
+        for reg in &mut [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.dma,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            **reg = dec.u8()?;
+        }
+        self.irq_vblank = dec.bool()?;
+        self.irq_lcdc = dec.bool()?;
+        self.counter = dec.u16()?;
+        dec.read_into(&mut self.frame_buffer)?;
+
+        // Save states before version 1.3 may hold brightness values instead
+        // of shades. Shades never exceed 3, so any larger value tells them
+        // apart (an all-black frame reads as white until the next frame).
+        if minor < 3 && self.frame_buffer.iter().any(|&b| b > 3) {
+            for b in self.frame_buffer.iter_mut() {
+                *b = match *b {
+                    0xc0..=0xff => 0,
+                    0x80..=0xbf => 1,
+                    0x40..=0x7f => 2,
+                    _ => 3,
+                };
+            }
+        }
+
+        Ok(())
+    }
+
     /// Checks LYC interrupt.
     fn update_lyc_interrupt(&mut self) {
         // LYC=LY coincidence interrupt
@@ -459,7 +518,7 @@ impl IODevice for PPU {
                 if self.counter >= 172 {
                     self.counter -= 172;
                     // Transition to H-Blank mode
-                    self.stat = self.stat & 0xf8;
+                    self.stat &= 0xf8;
                     self.update_mode_interrupt();
                 }
             }
@@ -502,3 +561,46 @@ impl IODevice for PPU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a PPU state with a given frame buffer.
+    fn state(frame_buffer: &[u8]) -> Vec<u8> {
+        let mut ppu = PPU::new();
+        ppu.frame_buffer.copy_from_slice(frame_buffer);
+        ppu.save_state()
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let frame: Vec<u8> = (0..SCREEN_W as usize * SCREEN_H as usize)
+            .map(|i| (i % 4) as u8)
+            .collect();
+
+        let mut ppu = PPU::new();
+        ppu.load_state(&state(&frame), 3).unwrap();
+        assert_eq!(ppu.frame_buffer(), &frame[..]);
+
+        // Shades written by version 1.2 are kept as they are
+        ppu.load_state(&state(&frame), 2).unwrap();
+        assert_eq!(ppu.frame_buffer(), &frame[..]);
+    }
+
+    #[test]
+    fn brightness_frame_buffer() {
+        let brightness = [0xff, 0xaa, 0x55, 0x00];
+        let frame: Vec<u8> = (0..SCREEN_W as usize * SCREEN_H as usize)
+            .map(|i| brightness[i % 4])
+            .collect();
+
+        let mut ppu = PPU::new();
+        ppu.load_state(&state(&frame), 1).unwrap();
+        assert!(ppu
+            .frame_buffer()
+            .iter()
+            .enumerate()
+            .all(|(i, &shade)| shade == (i % 4) as u8));
+    }
+}
@@ -0,0 +1,468 @@
+//! Game Boy Printer connected to the serial port.
+//!
+//! The Game Boy sends packets of the form:
+//!
+//! ```text
+//! 0x88 0x33 CMD COMPRESSION LEN_LO LEN_HI DATA... SUM_LO SUM_HI 0x00 0x00
+//! ```
+//!
+//! where the checksum is the sum of all bytes from `CMD` to the end of the
+//! data. The printer replies 0x81 to the first trailing byte and its status to
+//! the second one.
+
+use std::iter;
+use std::path::PathBuf;
+
+use image;
+use palette::Palette;
+use serial::SerialDevice;
+
+/// Width of a printout in pixels.
+pub const PRINT_W: usize = 160;
+
+/// Number of bytes in a band of 2 rows of 20 tiles.
+const BAND_SIZE: usize = 640;
+/// Maximum number of bands in the image buffer.
+const MAX_BANDS: usize = 9;
+/// Number of status packets for which the printer reports to be busy after a
+/// print command.
+const PRINT_BUSY_PACKETS: u8 = 4;
+
+/// Reply to the first trailing byte of a packet.
+const DEVICE_ID: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+/// Position within a packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/// Decompresses RLE-encoded image data. A control byte with bit 7 set repeats
+/// the next byte (control & 0x7f) + 2 times, otherwise (control + 1) literal
+/// bytes follow.
+pub fn decompress(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < buf.len() {
+        let ctrl = buf[i];
+        i += 1;
+
+        if ctrl & 0x80 > 0 {
+            let len = (ctrl & 0x7f) as usize + 2;
+            if let Some(&val) = buf.get(i) {
+                out.extend(iter::repeat(val).take(len));
+            }
+            i += 1;
+        } else {
+            let len = ctrl as usize + 1;
+            let end = (i + len).min(buf.len());
+            out.extend_from_slice(&buf[i..end]);
+            i = end;
+        }
+    }
+
+    out
+}
+
+/// Converts image data (bands of 2 rows of 20 tiles in 2bpp format) to
+/// shades, mapping each colour number through the print palette.
+pub fn render(data: &[u8], palette: u8) -> Vec<u8> {
+    let tile_rows = data.len() / (BAND_SIZE / 2);
+    let mut shades = vec![0; PRINT_W * tile_rows * 8];
+
+    for (tile_no, tile) in data.chunks(16).take(tile_rows * 20).enumerate() {
+        let tx = tile_no % 20;
+        let ty = tile_no / 20;
+
+        for y in 0..8 {
+            let lo = tile[y * 2];
+            let hi = tile[y * 2 + 1];
+
+            for x in 0..8 {
+                let bit = 7 - x;
+                let color_no = (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1);
+                let shade = (palette >> (color_no << 1)) & 3;
+
+                shades[(ty * 8 + y) * PRINT_W + tx * 8 + x] = shade;
+            }
+        }
+    }
+
+    shades
+}
+
+/// Game Boy Printer that saves each printout as a PNG strip.
+pub struct Printer {
+    /// Directory where printouts are saved
+    dir: PathBuf,
+    /// Number of printouts saved so far
+    count: u32,
+    /// Colours used for printouts
+    colors: Palette,
+
+    /// Position within the current packet
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    /// Checksum received in the packet
+    expected_checksum: u16,
+
+    /// Decompressed image data received since the last print
+    image: Vec<u8>,
+    /// Status reported to the Game Boy
+    status: u8,
+    /// Remaining status packets while printing
+    busy: u8,
+}
+
+impl Printer {
+    /// Creates a new `Printer` that saves printouts to a given directory.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Printer {
+            dir: dir.into(),
+            count: 0,
+            colors: Palette::default(),
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            expected_checksum: 0,
+            image: Vec::new(),
+            status: 0,
+            busy: 0,
+        }
+    }
+
+    /// Executes a received packet.
+    fn execute(&mut self) {
+        if self.checksum != self.expected_checksum {
+            warn!("Printer packet has a bad checksum");
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            CMD_INIT => {
+                self.image.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            CMD_PRINT => {
+                if self.data.len() < 4 {
+                    self.status |= STATUS_PACKET_ERROR;
+                    return;
+                }
+
+                // Data is number of sheets, margins, palette and exposure
+                let sheets = self.data[0];
+                let palette = self.data[2];
+                if sheets > 0 {
+                    self.print(palette);
+                }
+
+                self.image.clear();
+                self.status = (self.status | STATUS_PRINTING) & !(STATUS_FULL | STATUS_UNPROCESSED);
+                self.busy = PRINT_BUSY_PACKETS;
+            }
+            CMD_DATA => {
+                // An empty data packet marks the end of the image
+                if self.data.is_empty() {
+                    self.status |= STATUS_FULL;
+                    return;
+                }
+
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+
+                if self.image.len() + data.len() > BAND_SIZE * MAX_BANDS {
+                    warn!("Printer image buffer overflowed");
+                    self.status |= STATUS_PACKET_ERROR;
+                    return;
+                }
+
+                self.image.extend(data);
+                self.status |= STATUS_UNPROCESSED;
+            }
+            CMD_STATUS => {
+                if self.busy > 0 {
+                    self.busy -= 1;
+                    if self.busy == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+            }
+            _ => {
+                warn!("Unknown printer command: 0x{:02x}", self.command);
+                self.status |= STATUS_PACKET_ERROR;
+            }
+        }
+    }
+
+    /// Saves the image buffer as a PNG strip.
+    fn print(&mut self, palette: u8) {
+        // A palette of 0 is treated as the default palette
+        let palette = if palette == 0 { 0xe4 } else { palette };
+
+        let shades = render(&self.image, palette);
+        let height = shades.len() / PRINT_W;
+        if height == 0 {
+            return;
+        }
+
+        self.count += 1;
+        let fname = self.dir.join(format!("print-{:04}.png", self.count));
+        let fname = fname.to_string_lossy();
+
+        info!("Printing to: {}", fname);
+
+        if let Err(e) = image::write_png(
+            &fname,
+            PRINT_W as u32,
+            height as u32,
+            &self.colors.apply(&shades),
+        ) {
+            warn!("Failed to write printout {}: {}", fname, e);
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, val: u8) -> Option<u8> {
+        let mut reply = 0x00;
+
+        self.state = match self.state {
+            PacketState::Magic1 if val == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if val == 0x33 => PacketState::Command,
+            PacketState::Magic2 if val == 0x88 => PacketState::Magic2,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = val;
+                self.checksum = val as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = val & 1 > 0;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = val as u16;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (val as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.data.clear();
+
+                if self.length > 0 {
+                    PacketState::Data
+                } else {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::Data => {
+                self.data.push(val);
+                self.checksum = self.checksum.wrapping_add(val as u16);
+
+                if self.data.len() < self.length as usize {
+                    PacketState::Data
+                } else {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.expected_checksum = val as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.expected_checksum |= (val as u16) << 8;
+                PacketState::DeviceId
+            }
+            PacketState::DeviceId => {
+                reply = DEVICE_ID;
+                self.execute();
+                PacketState::Status
+            }
+            PacketState::Status => {
+                reply = self.status;
+                PacketState::Magic1
+            }
+        };
+
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a packet with a given command and data, and a valid checksum.
+    fn packet(command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut buf = vec![command, compression, len as u8, (len >> 8) as u8];
+        buf.extend_from_slice(data);
+
+        let sum = buf.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        buf.extend_from_slice(&[sum as u8, (sum >> 8) as u8, 0x00, 0x00]);
+
+        let mut out = vec![0x88, 0x33];
+        out.extend(buf);
+        out
+    }
+
+    /// Sends bytes to the printer and returns the replies.
+    fn send(printer: &mut Printer, buf: &[u8]) -> Vec<u8> {
+        buf.iter().map(|&b| printer.transfer(b).unwrap()).collect()
+    }
+
+    /// Sends a packet and returns the device ID and status replies.
+    fn send_packet(printer: &mut Printer, buf: &[u8]) -> (u8, u8) {
+        let replies = send(printer, buf);
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    #[test]
+    fn decompress_literals() {
+        assert_eq!(decompress(&[0x02, 1, 2, 3]), vec![1, 2, 3]);
+        assert_eq!(decompress(&[0x00, 7, 0x01, 8, 9]), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn decompress_runs() {
+        assert_eq!(decompress(&[0x80, 0xaa]), vec![0xaa; 2]);
+        assert_eq!(decompress(&[0xff, 0x55]), vec![0x55; 129]);
+    }
+
+    #[test]
+    fn decompress_mixed() {
+        assert_eq!(
+            decompress(&[0x81, 0x11, 0x01, 0x22, 0x33, 0x80, 0x44]),
+            vec![0x11, 0x11, 0x11, 0x22, 0x33, 0x44, 0x44]
+        );
+    }
+
+    #[test]
+    fn decompress_truncated() {
+        assert_eq!(decompress(&[0x03, 1, 2]), vec![1, 2]);
+        assert_eq!(decompress(&[0x85]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn status_packet() {
+        let mut printer = Printer::new("");
+        let replies = send(&mut printer, &packet(CMD_STATUS, 0, &[]));
+
+        assert!(replies[..replies.len() - 2].iter().all(|&b| b == 0x00));
+        assert_eq!(replies[replies.len() - 2], DEVICE_ID);
+        assert_eq!(replies[replies.len() - 1], 0x00);
+        assert_eq!(printer.state, PacketState::Magic1);
+    }
+
+    #[test]
+    fn data_packet() {
+        let mut printer = Printer::new("");
+        let data = vec![0x12; BAND_SIZE];
+
+        let (id, status) = send_packet(&mut printer, &packet(CMD_DATA, 0, &data));
+        assert_eq!(id, DEVICE_ID);
+        assert_eq!(status, STATUS_UNPROCESSED);
+        assert_eq!(printer.image, data);
+
+        // An empty data packet marks the end of the image
+        let (_, status) = send_packet(&mut printer, &packet(CMD_DATA, 0, &[]));
+        assert_eq!(status, STATUS_UNPROCESSED | STATUS_FULL);
+    }
+
+    #[test]
+    fn compressed_data_packet() {
+        let mut printer = Printer::new("");
+
+        send_packet(
+            &mut printer,
+            &packet(CMD_DATA, 1, &[0x81, 0xaa, 0x00, 0x55]),
+        );
+        assert_eq!(printer.image, vec![0xaa, 0xaa, 0xaa, 0x55]);
+    }
+
+    #[test]
+    fn init_packet() {
+        let mut printer = Printer::new("");
+        send_packet(&mut printer, &packet(CMD_DATA, 0, &[0x12; 16]));
+
+        let (_, status) = send_packet(&mut printer, &packet(CMD_INIT, 0, &[]));
+        assert_eq!(status, 0x00);
+        assert!(printer.image.is_empty());
+    }
+
+    #[test]
+    fn checksum_error() {
+        let mut printer = Printer::new("");
+        let mut buf = packet(CMD_DATA, 0, &[0x12; 16]);
+        let sum_lo = buf.len() - 4;
+        buf[sum_lo] ^= 0xff;
+
+        let (id, status) = send_packet(&mut printer, &buf);
+        assert_eq!(id, DEVICE_ID);
+        assert_eq!(status, STATUS_CHECKSUM_ERROR);
+        assert!(printer.image.is_empty());
+
+        // The error is cleared by the next valid packet
+        let (_, status) = send_packet(&mut printer, &packet(CMD_STATUS, 0, &[]));
+        assert_eq!(status, 0x00);
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut printer = Printer::new("");
+
+        let (_, status) = send_packet(&mut printer, &packet(0x7f, 0, &[]));
+        assert_eq!(status, STATUS_PACKET_ERROR);
+    }
+
+    #[test]
+    fn resync_on_magic() {
+        let mut printer = Printer::new("");
+
+        // Garbage before the packet and a repeated first magic byte
+        let mut buf = vec![0x00, 0x33, 0x88, 0x88, 0x88];
+        buf.extend_from_slice(&packet(CMD_STATUS, 0, &[])[1..]);
+
+        let (id, _) = send_packet(&mut printer, &buf);
+        assert_eq!(id, DEVICE_ID);
+        assert_eq!(printer.state, PacketState::Magic1);
+    }
+}
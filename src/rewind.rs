@@ -0,0 +1,150 @@
+//! Rewind buffer.
+//!
+//! Snapshots of the machine are taken periodically while the game is running.
+//! Only the newest snapshot is kept as is; older ones are stored as the
+//! compressed XOR difference to their successor, which is mostly zeros since
+//! little memory changes within a few frames. Walking back applies the
+//! differences in reverse order.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use gameboy::GameBoy;
+
+/// Ring buffer of snapshots for rewinding.
+pub struct Rewind {
+    /// Number of frames between two snapshots
+    interval: u32,
+    /// Maximum number of bytes used by snapshots
+    budget: usize,
+    /// Frames elapsed since the last snapshot
+    frames: u32,
+    /// Newest snapshot
+    current: Option<Vec<u8>>,
+    /// Compressed differences between consecutive snapshots, oldest first
+    deltas: VecDeque<Vec<u8>>,
+    /// Total size of the compressed differences
+    deltas_size: usize,
+}
+
+/// XORs `src` into `dst`.
+fn xor(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn compress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut enc = DeflateEncoder::new(Vec::new(), Compression::fast());
+    enc.write_all(buf)?;
+    enc.finish()
+}
+
+fn decompress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(buf).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+impl Rewind {
+    /// Creates a new `Rewind` that takes a snapshot every `interval` frames
+    /// and keeps at most `budget` bytes of snapshots.
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            current: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    /// Returns the number of bytes currently used by snapshots.
+    pub fn size(&self) -> usize {
+        self.current.as_ref().map_or(0, |s| s.len()) + self.deltas_size
+    }
+
+    /// Discards all snapshots.
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.current = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+
+    /// Should be called after each emulated frame. Takes a snapshot if the
+    /// interval has elapsed.
+    pub fn push(&mut self, gameboy: &GameBoy) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = gameboy.save_state();
+
+        if let Some(mut prev) = self.current.take() {
+            if prev.len() == state.len() {
+                xor(&mut prev, &state);
+
+                match compress(&prev) {
+                    Ok(delta) => {
+                        self.deltas_size += delta.len();
+                        self.deltas.push_back(delta);
+                    }
+                    Err(e) => {
+                        warn!("Failed to compress snapshot: {}", e);
+                        self.clear();
+                    }
+                }
+            } else {
+                self.clear();
+            }
+        }
+
+        self.current = Some(state);
+
+        // Drop the oldest snapshots until we are within budget
+        while self.size() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Restores the snapshot preceding the newest one. Returns `false` if
+    /// there is no older snapshot left.
+    pub fn pop(&mut self, gameboy: &mut GameBoy) -> bool {
+        self.frames = 0;
+
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.deltas_size -= delta.len();
+
+        let current = match self.current {
+            Some(ref mut current) => current,
+            None => return false,
+        };
+
+        let res = decompress(&delta).and_then(|delta| {
+            xor(current, &delta);
+            gameboy.load_state(current)
+        });
+
+        if let Err(e) = res {
+            warn!("Failed to restore snapshot: {}", e);
+            self.clear();
+            return false;
+        }
+
+        true
+    }
+}
@@ -0,0 +1,199 @@
+//! Battery save file formats.
+//!
+//! Emulators and flash carts mostly agree on storing the raw contents of
+//! external RAM, but differ in what they put around it:
+//!
+//! - `.sav` files of BGB, VBA-M, mGBA and SameBoy append a 48-byte (or legacy
+//!   44-byte) RTC footer for MBC3 catridges with a clock
+//! - `.srm` files of RetroArch and most flash carts contain RAM only
+//! - some tools pad the file to a fixed size or drop trailing RAM banks
+
+use std::fmt;
+
+/// How the contents of a save file relate to the expected RAM size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveLayout {
+    /// Exactly the RAM size
+    Raw,
+    /// RAM followed by a footer accepted by the mapper
+    WithFooter(usize),
+    /// RAM followed by a given number of unknown bytes, which are dropped
+    Padded(usize),
+    /// RAM missing a given number of trailing bytes, which are filled with 0xff
+    Truncated(usize),
+}
+
+impl SaveLayout {
+    /// Returns whether the file can be used without conversion.
+    pub fn is_native(&self) -> bool {
+        matches!(*self, SaveLayout::Raw | SaveLayout::WithFooter(_))
+    }
+}
+
+impl fmt::Display for SaveLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveLayout::Raw => write!(f, "raw RAM"),
+            SaveLayout::WithFooter(n) => write!(f, "RAM with {}-byte RTC footer", n),
+            SaveLayout::Padded(n) => write!(f, "RAM padded with {} extra bytes", n),
+            SaveLayout::Truncated(n) => write!(f, "RAM truncated by {} bytes", n),
+        }
+    }
+}
+
+/// Format of an exported save file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveFormat {
+    /// RAM followed by the RTC footer, if any
+    Sav,
+    /// RAM only
+    Srm,
+}
+
+impl SaveFormat {
+    /// Returns the format conventionally used for a file name.
+    pub fn from_fname(fname: &str) -> Self {
+        if fname.to_lowercase().ends_with(".srm") {
+            SaveFormat::Srm
+        } else {
+            SaveFormat::Sav
+        }
+    }
+}
+
+impl fmt::Display for SaveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveFormat::Sav => write!(f, "sav"),
+            SaveFormat::Srm => write!(f, "srm"),
+        }
+    }
+}
+
+/// Detects the layout of a save file for a given RAM size and footer sizes
+/// accepted by the mapper.
+pub fn detect(buf: &[u8], ram_size: usize, footer_sizes: &[usize]) -> SaveLayout {
+    if buf.len() < ram_size {
+        SaveLayout::Truncated(ram_size - buf.len())
+    } else if buf.len() == ram_size {
+        SaveLayout::Raw
+    } else if footer_sizes.contains(&(buf.len() - ram_size)) {
+        SaveLayout::WithFooter(buf.len() - ram_size)
+    } else {
+        SaveLayout::Padded(buf.len() - ram_size)
+    }
+}
+
+/// Splits a save file into RAM contents of a given size and footer
+/// according to its layout.
+pub fn split(buf: &[u8], ram_size: usize, layout: SaveLayout) -> (Vec<u8>, &[u8]) {
+    let len = buf.len().min(ram_size);
+    let mut ram = buf[..len].to_vec();
+    ram.resize(ram_size, 0xff);
+
+    let footer = match layout {
+        SaveLayout::WithFooter(_) => &buf[ram_size..],
+        _ => &[],
+    };
+
+    (ram, footer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+
+    const RAM_SIZE: usize = 32 * 1024;
+
+    /// Returns the footer sizes accepted by MBC3 with a clock.
+    fn rtc_footer_sizes() -> &'static [usize] {
+        mapper::new_mapper(0x10).unwrap().footer_sizes()
+    }
+
+    /// Returns a save file of a given size filled with a pattern.
+    fn save(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn raw() {
+        let buf = save(RAM_SIZE);
+        let layout = detect(&buf, RAM_SIZE, rtc_footer_sizes());
+        assert_eq!(layout, SaveLayout::Raw);
+        assert!(layout.is_native());
+
+        let (ram, footer) = split(&buf, RAM_SIZE, layout);
+        assert_eq!(ram, buf);
+        assert!(footer.is_empty());
+    }
+
+    #[test]
+    fn rtc_footer() {
+        for &footer_size in &[48, 44] {
+            let buf = save(RAM_SIZE + footer_size);
+            let layout = detect(&buf, RAM_SIZE, rtc_footer_sizes());
+            assert_eq!(layout, SaveLayout::WithFooter(footer_size));
+            assert!(layout.is_native());
+
+            let (ram, footer) = split(&buf, RAM_SIZE, layout);
+            assert_eq!(ram, &buf[..RAM_SIZE]);
+            assert_eq!(footer, &buf[RAM_SIZE..]);
+        }
+    }
+
+    #[test]
+    fn rtc_footer_without_rtc() {
+        // The same file is padding for a mapper without a clock
+        let buf = save(RAM_SIZE + 48);
+        let layout = detect(&buf, RAM_SIZE, &[]);
+        assert_eq!(layout, SaveLayout::Padded(48));
+        assert!(!layout.is_native());
+    }
+
+    #[test]
+    fn padded() {
+        let buf = save(RAM_SIZE * 4);
+        let layout = detect(&buf, RAM_SIZE, rtc_footer_sizes());
+        assert_eq!(layout, SaveLayout::Padded(RAM_SIZE * 3));
+        assert!(!layout.is_native());
+
+        let (ram, footer) = split(&buf, RAM_SIZE, layout);
+        assert_eq!(ram, &buf[..RAM_SIZE]);
+        assert!(footer.is_empty());
+    }
+
+    #[test]
+    fn odd_size() {
+        let buf = save(RAM_SIZE + 47);
+        let layout = detect(&buf, RAM_SIZE, rtc_footer_sizes());
+        assert_eq!(layout, SaveLayout::Padded(47));
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = save(8 * 1024);
+        let layout = detect(&buf, RAM_SIZE, rtc_footer_sizes());
+        assert_eq!(layout, SaveLayout::Truncated(RAM_SIZE - 8 * 1024));
+        assert!(!layout.is_native());
+
+        let (ram, footer) = split(&buf, RAM_SIZE, layout);
+        assert_eq!(ram.len(), RAM_SIZE);
+        assert_eq!(&ram[..buf.len()], &buf[..]);
+        assert!(ram[buf.len()..].iter().all(|&b| b == 0xff));
+        assert!(footer.is_empty());
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(detect(&[], RAM_SIZE, &[]), SaveLayout::Truncated(RAM_SIZE));
+        assert_eq!(detect(&[], 0, &[]), SaveLayout::Raw);
+    }
+
+    #[test]
+    fn format_from_fname() {
+        assert_eq!(SaveFormat::from_fname("game.sav"), SaveFormat::Sav);
+        assert_eq!(SaveFormat::from_fname("game.SRM"), SaveFormat::Srm);
+        assert_eq!(SaveFormat::from_fname("game"), SaveFormat::Sav);
+    }
+}
@@ -0,0 +1,319 @@
+use std::io;
+
+use io_device::IODevice;
+use state::{Decoder, Encoder};
+
+/// Number of clocks to transfer a byte with the internal clock (8192 Hz).
+const TRANSFER_TICKS: u16 = 8 * 512;
+/// Number of clocks between two polls of a device that has not replied yet
+/// (one bit at 8192 Hz).
+const POLL_TICKS: u16 = 512;
+
+/// Maximum number of sent bytes kept until they are taken.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// A device connected to the other end of the link cable.
+pub trait SerialDevice {
+    /// Exchanges a byte in a transfer clocked by the Game Boy and returns the
+    /// byte received from the device. Devices that can't reply right away
+    /// return `None`, and `poll_reply` is called until they do.
+    fn transfer(&mut self, val: u8) -> Option<u8>;
+
+    /// Checks whether the reply to a transfer that returned `None` has
+    /// arrived. Returns the received byte if so.
+    fn poll_reply(&mut self) -> Option<u8> {
+        Some(0xff)
+    }
+
+    /// Checks whether the device has clocked a transfer while the Game Boy
+    /// waits for the external clock. Returns the byte received from the
+    /// device if so, in which case `val` was sent to it.
+    fn poll_external(&mut self, _val: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Serial port.
+pub struct Serial {
+    /// Serial transfer data
+    sb: u8,
+    /// Serial transfer control
+    sc: u8,
+    /// Clocks until the current transfer completes
+    counter: u16,
+    /// Whether the device is yet to reply to the current transfer
+    waiting: bool,
+    /// Clocks since the device was last polled
+    poll_counter: u16,
+    /// Device connected to the port
+    device: Option<Box<dyn SerialDevice>>,
+    /// Bytes sent with the internal clock since they were last taken
+    output: Vec<u8>,
+    /// Interrupt request
+    pub irq: bool,
+}
+
+impl Serial {
+    /// Creates a new `Serial` with nothing connected.
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            counter: 0,
+            waiting: false,
+            poll_counter: 0,
+            device: None,
+            output: Vec::new(),
+            irq: false,
+        }
+    }
+
+    /// Connects a device to the port, or disconnects it if `None`.
+    pub fn set_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.device = device;
+    }
+
+    /// Returns the bytes sent with the internal clock since they were last
+    /// taken.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Takes the bytes sent with the internal clock since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.split_off(0)
+    }
+
+    /// Returns whether a transfer has been requested.
+    fn transfer_requested(&self) -> bool {
+        self.sc & 0x80 > 0
+    }
+
+    /// Returns whether the Game Boy drives the clock.
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 > 0
+    }
+
+    /// Completes a transfer with the received byte.
+    fn complete(&mut self, val: u8) {
+        self.sb = val;
+        self.sc &= 0x7f;
+        self.waiting = false;
+        self.irq = true;
+    }
+
+    /// Advances the poll counter and returns whether the device should be
+    /// polled. Polling is rate limited as it may query a socket.
+    fn poll_due(&mut self, tick: u8) -> bool {
+        self.poll_counter += tick as u16;
+        if self.poll_counter < POLL_TICKS {
+            return false;
+        }
+
+        self.poll_counter = 0;
+        true
+    }
+
+    /// Serializes the serial port state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.u8(self.sb);
+        enc.u8(self.sc);
+        enc.u16(self.counter);
+        enc.bool(self.irq);
+        enc.finish()
+    }
+
+    /// Restores the serial port state.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+        self.sb = dec.u8()?;
+        self.sc = dec.u8()?;
+        self.counter = dec.u16()?;
+        self.irq = dec.bool()?;
+
+        Ok(())
+    }
+}
+
+impl IODevice for Serial {
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // SB
+            0xff01 => self.sb = val,
+            // SC
+            0xff02 => {
+                self.sc = val & 0x81;
+
+                if self.transfer_requested() && self.internal_clock() {
+                    self.counter = TRANSFER_TICKS;
+                    self.waiting = false;
+                }
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // SB
+            0xff01 => self.sb,
+            // SC
+            0xff02 => self.sc | 0x7e,
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn update(&mut self, tick: u8) {
+        if !self.transfer_requested() {
+            return;
+        }
+
+        if self.internal_clock() {
+            if self.counter > tick as u16 {
+                self.counter -= tick as u16;
+                return;
+            }
+            self.counter = 0;
+
+            let val = if self.waiting {
+                if !self.poll_due(tick) {
+                    return;
+                }
+
+                match self.device {
+                    Some(ref mut device) => device.poll_reply(),
+                    None => Some(0xff),
+                }
+            } else {
+                if self.output.len() >= MAX_OUTPUT {
+                    self.output.remove(0);
+                }
+                self.output.push(self.sb);
+
+                // Without a device, all bits read as 1
+                match self.device {
+                    Some(ref mut device) => device.transfer(self.sb),
+                    None => Some(0xff),
+                }
+            };
+
+            match val {
+                Some(val) => self.complete(val),
+                None => {
+                    self.waiting = true;
+                    self.poll_counter = 0;
+                }
+            }
+        } else {
+            if !self.poll_due(tick) {
+                return;
+            }
+
+            let sb = self.sb;
+            let val = match self.device {
+                Some(ref mut device) => device.poll_external(sb),
+                None => None,
+            };
+
+            if let Some(val) = val {
+                self.complete(val);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Device that replies to a transfer after being polled a number of
+    /// times, and counts the polls.
+    struct SlowDevice {
+        delay: u32,
+        polls: Rc<Cell<u32>>,
+    }
+
+    impl SerialDevice for SlowDevice {
+        fn transfer(&mut self, val: u8) -> Option<u8> {
+            assert_eq!(val, 0x42);
+            None
+        }
+
+        fn poll_reply(&mut self) -> Option<u8> {
+            self.polls.set(self.polls.get() + 1);
+            if self.polls.get() < self.delay {
+                None
+            } else {
+                Some(0x24)
+            }
+        }
+
+        fn poll_external(&mut self, _val: u8) -> Option<u8> {
+            self.polls.set(self.polls.get() + 1);
+            None
+        }
+    }
+
+    /// Advances the serial port by a number of clocks in M-cycle steps.
+    fn run(serial: &mut Serial, ticks: u32) {
+        for _ in 0..ticks / 4 {
+            serial.update(4);
+        }
+    }
+
+    #[test]
+    fn internal_clock_without_device() {
+        let mut serial = Serial::new();
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x81);
+
+        run(&mut serial, TRANSFER_TICKS as u32 - 4);
+        assert!(!serial.irq);
+        run(&mut serial, 4);
+
+        assert!(serial.irq);
+        assert_eq!(serial.read(0xff01), 0xff);
+        assert_eq!(serial.read(0xff02), 0x7f);
+        assert_eq!(serial.take_output(), vec![0x42]);
+    }
+
+    #[test]
+    fn internal_clock_late_reply() {
+        let polls = Rc::new(Cell::new(0));
+        let mut serial = Serial::new();
+        serial.set_device(Some(Box::new(SlowDevice {
+            delay: 3,
+            polls: polls.clone(),
+        })));
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x81);
+
+        // The transfer stays in progress until the device replies
+        run(&mut serial, TRANSFER_TICKS as u32 + 2 * POLL_TICKS as u32);
+        assert!(!serial.irq);
+        assert_eq!(polls.get(), 2);
+
+        run(&mut serial, POLL_TICKS as u32);
+        assert!(serial.irq);
+        assert_eq!(serial.read(0xff01), 0x24);
+        assert_eq!(serial.take_output(), vec![0x42]);
+    }
+
+    #[test]
+    fn external_clock_polls_rarely() {
+        let polls = Rc::new(Cell::new(0));
+        let mut serial = Serial::new();
+        serial.set_device(Some(Box::new(SlowDevice {
+            delay: 0,
+            polls: polls.clone(),
+        })));
+        serial.write(0xff02, 0x80);
+
+        run(&mut serial, 10 * POLL_TICKS as u32);
+        assert_eq!(polls.get(), 10);
+        assert!(!serial.irq);
+    }
+}
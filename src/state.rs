@@ -0,0 +1,235 @@
+//! Save state format.
+//!
+//! A save state starts with a magic number and a format version, followed by
+//! a sequence of chunks. Each chunk has a four-byte tag, a 32-bit length and
+//! the serialized fields of one component.
+//!
+//! The format is forward-compatible within the same major version: readers
+//! skip unknown chunks and ignore trailing fields that newer versions append
+//! to a chunk.
+
+use std::io;
+
+/// Magic number at the start of a save state.
+const MAGIC: &[u8; 4] = b"GBRS";
+/// Major format version. Incremented on incompatible changes.
+const VERSION_MAJOR: u16 = 1;
+/// Minor format version. Incremented when chunks or fields are added, or
+/// when the meaning of a field changes in a way that old readers tolerate.
+const VERSION_MINOR: u16 = 3;
+
+/// Returns an `InvalidData` error with a given message.
+fn invalid<T>(msg: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Writes a save state.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Creates a new `StateWriter` and writes the header.
+    pub fn new() -> Self {
+        let mut enc = Encoder::new();
+        enc.bytes(MAGIC);
+        enc.u16(VERSION_MAJOR);
+        enc.u16(VERSION_MINOR);
+
+        StateWriter { buf: enc.finish() }
+    }
+
+    /// Appends a chunk.
+    pub fn chunk(&mut self, tag: &[u8; 4], data: &[u8]) {
+        self.buf.extend_from_slice(tag);
+        self.buf
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the serialized save state.
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads a save state.
+pub struct StateReader<'a> {
+    /// Minor format version of the save state
+    minor: u16,
+    chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> StateReader<'a> {
+    /// Parses the header and chunk list of a save state.
+    pub fn new(buf: &'a [u8]) -> io::Result<Self> {
+        let mut dec = Decoder::new(buf);
+
+        if dec.bytes(4)? != MAGIC {
+            return invalid(String::from("Not a save state"));
+        }
+
+        let major = dec.u16()?;
+        let minor = dec.u16()?;
+        if major != VERSION_MAJOR {
+            return invalid(format!(
+                "Unsupported save state version {}.{}",
+                major, minor
+            ));
+        }
+
+        let mut chunks = Vec::new();
+        while !dec.is_empty() {
+            let mut tag = [0; 4];
+            tag.copy_from_slice(dec.bytes(4)?);
+            let len = dec.u32()? as usize;
+            chunks.push((tag, dec.bytes(len)?));
+        }
+
+        Ok(StateReader { minor, chunks })
+    }
+
+    /// Returns the minor format version of the save state.
+    pub fn minor(&self) -> u16 {
+        self.minor
+    }
+
+    /// Returns the contents of a chunk, or `None` if the save state has no
+    /// such chunk.
+    pub fn find(&self, tag: &[u8; 4]) -> Option<&'a [u8]> {
+        self.chunks
+            .iter()
+            .find(|chunk| &chunk.0 == tag)
+            .map(|chunk| chunk.1)
+    }
+
+    /// Returns the contents of a chunk.
+    pub fn chunk(&self, tag: &[u8; 4]) -> io::Result<&'a [u8]> {
+        match self.find(tag) {
+            Some(chunk) => Ok(chunk),
+            None => invalid(format!(
+                "Save state has no {} chunk",
+                String::from_utf8_lossy(tag).trim()
+            )),
+        }
+    }
+}
+
+/// Serializes the fields of a component.
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// Creates a new `Encoder`.
+    pub fn new() -> Self {
+        Encoder { buf: Vec::new() }
+    }
+
+    /// Writes a byte.
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    /// Writes a boolean.
+    pub fn bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    /// Writes a little-endian 16-bit integer.
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Writes a little-endian 32-bit integer.
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Writes a fixed-length byte array.
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    /// Writes a variable-length byte array prefixed with its length.
+    pub fn blob(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes(val);
+    }
+
+    /// Returns the serialized fields.
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Deserializes the fields of a component.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a new `Decoder`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, pos: 0 }
+    }
+
+    /// Returns whether all data has been consumed.
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Reads a fixed-length byte array.
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Save state is truncated",
+            ));
+        }
+
+        let val = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(val)
+    }
+
+    /// Reads a byte.
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads a boolean.
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? > 0)
+    }
+
+    /// Reads a little-endian 16-bit integer.
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    /// Reads a little-endian 32-bit integer.
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+
+        Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+
+    /// Reads a fixed-length byte array into a slice.
+    pub fn read_into(&mut self, dst: &mut [u8]) -> io::Result<()> {
+        dst.copy_from_slice(self.bytes(dst.len())?);
+
+        Ok(())
+    }
+
+    /// Reads a variable-length byte array prefixed with its length.
+    pub fn blob(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+
+        self.bytes(len)
+    }
+}
@@ -1,4 +1,7 @@
+use std::io;
+
 use io_device::IODevice;
+use state::{Decoder, Encoder};
 
 pub struct Timer {
     /// Timer counter
@@ -24,6 +27,29 @@ impl Timer {
             irq: false,
         }
     }
+
+    /// Serializes the timer state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.u8(self.tima);
+        enc.u8(self.tma);
+        enc.u8(self.tac);
+        enc.u16(self.counter);
+        enc.bool(self.irq);
+        enc.finish()
+    }
+
+    /// Restores the timer state.
+    pub fn load_state(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut dec = Decoder::new(buf);
+        self.tima = dec.u8()?;
+        self.tma = dec.u8()?;
+        self.tac = dec.u8()?;
+        self.counter = dec.u16()?;
+        self.irq = dec.bool()?;
+
+        Ok(())
+    }
 }
 
 impl IODevice for Timer {
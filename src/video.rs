@@ -0,0 +1,158 @@
+//! Video recording.
+//!
+//! Emulated frames can be recorded as numbered PNG files, as an animated GIF
+//! or as a raw RGB24 stream on stdout that can be piped into an external
+//! encoder, e.g.:
+//!
+//! ```text
+//! gbr-headless --video - rom.gb | ffmpeg -f rawvideo -pix_fmt rgb24 \
+//!     -s 160x144 -r 59.7275 -i - out.mp4
+//! ```
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::PathBuf;
+
+use gif;
+
+use image;
+use pacing::FRAME_RATE;
+use ppu::{SCREEN_H, SCREEN_W};
+
+/// Every n-th frame is written to GIFs, since most viewers do not support
+/// frame delays below 2/100 s.
+const GIF_FRAME_STEP: u32 = 2;
+
+/// Destination of recorded frames.
+enum Sink {
+    /// Numbered PNG files in a directory
+    Png(PathBuf),
+    /// Animated GIF
+    Gif(gif::Encoder<BufWriter<File>>),
+    /// Raw RGB24 stream on stdout
+    Raw(BufWriter<Stdout>),
+}
+
+/// Records emulated frames to a video.
+pub struct VideoRecorder {
+    sink: Sink,
+    /// Number of frames recorded so far
+    frames: u32,
+    /// Total delay of the frames written to a GIF in 1/100 s
+    gif_time: u32,
+}
+
+/// Converts a GIF encoding error to an I/O error.
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Converts an RGB24 image with at most 256 colors to an indexed frame.
+/// Returns `None` if there are too many colors.
+fn indexed_frame(rgb: &[u8]) -> Option<gif::Frame<'static>> {
+    let mut colors = HashMap::new();
+    let mut palette = Vec::new();
+    let mut buffer = Vec::with_capacity(rgb.len() / 3);
+
+    for pixel in rgb.chunks(3) {
+        let next = colors.len();
+        let index = *colors.entry((pixel[0], pixel[1], pixel[2])).or_insert(next);
+
+        if index == next {
+            if next == 256 {
+                return None;
+            }
+            palette.extend_from_slice(pixel);
+        }
+
+        buffer.push(index as u8);
+    }
+
+    Some(gif::Frame {
+        width: SCREEN_W as u16,
+        height: SCREEN_H as u16,
+        palette: Some(palette),
+        buffer: buffer.into(),
+        ..gif::Frame::default()
+    })
+}
+
+impl VideoRecorder {
+    /// Starts recording to a given path. The format is chosen from the path:
+    /// `-` writes raw RGB24 to stdout, `.gif` writes an animated GIF, and
+    /// anything else is a directory that numbered PNG files are written to.
+    pub fn create(path: &str) -> io::Result<Self> {
+        let sink = if path == "-" {
+            Sink::Raw(BufWriter::new(io::stdout()))
+        } else if path.to_lowercase().ends_with(".gif") {
+            let file = BufWriter::new(File::create(path)?);
+            let mut encoder = gif::Encoder::new(file, SCREEN_W as u16, SCREEN_H as u16, &[])
+                .map_err(gif_error)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(gif_error)?;
+
+            Sink::Gif(encoder)
+        } else {
+            fs::create_dir_all(path)?;
+
+            Sink::Png(PathBuf::from(path))
+        };
+
+        info!("Recording video to: {}", path);
+
+        Ok(VideoRecorder {
+            sink,
+            frames: 0,
+            gif_time: 0,
+        })
+    }
+
+    /// Appends a frame given as RGB24.
+    pub fn add_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let frame_no = self.frames;
+        self.frames += 1;
+
+        match self.sink {
+            Sink::Png(ref dir) => {
+                let fname = dir.join(format!("{:06}.png", frame_no));
+                let (w, h) = (SCREEN_W as u32, SCREEN_H as u32);
+
+                image::write_png(&fname.to_string_lossy(), w, h, rgb)
+            }
+            Sink::Gif(ref mut encoder) => {
+                if frame_no % GIF_FRAME_STEP != 0 {
+                    return Ok(());
+                }
+
+                // Delays are rounded so that their sum follows the frame rate
+                let end = ((frame_no + GIF_FRAME_STEP) as f64 * 100.0 / FRAME_RATE).round() as u32;
+                let mut frame = indexed_frame(rgb)
+                    .unwrap_or_else(|| gif::Frame::from_rgb(SCREEN_W as u16, SCREEN_H as u16, rgb));
+                frame.delay = (end - self.gif_time) as u16;
+                self.gif_time = end;
+
+                encoder.write_frame(&frame).map_err(gif_error)
+            }
+            Sink::Raw(ref mut out) => out.write_all(rgb),
+        }
+    }
+
+    /// Returns the number of frames recorded so far.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Finishes the recording and flushes all output.
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Png(_) => Ok(()),
+            Sink::Gif(encoder) => encoder.into_inner()?.flush(),
+            Sink::Raw(mut out) => out.flush(),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Runs the blargg and mooneye test ROMs and reports a pass/fail table.
+//!
+//! The ROMs are not distributed with gbr. Point `GBR_TEST_ROMS` to a
+//! directory laid out as follows, otherwise the tests are skipped:
+//!
+//! ```text
+//! $GBR_TEST_ROMS/blargg/cpu_instrs/**/*.gb
+//! $GBR_TEST_ROMS/blargg/instr_timing/**/*.gb
+//! $GBR_TEST_ROMS/mooneye/acceptance/**/*.gb
+//! ```
+//!
+//! Set `GBR_TEST_FILTER` to only run ROMs whose path contains a given string.
+//!
+//! ROMs listed in `known_failures.txt` are expected to fail. A suite only
+//! fails if any other ROM fails, so that it catches regressions.
+
+extern crate gbr;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use gbr::{Catridge, GameBoy};
+
+/// Number of clocks per second.
+const CLOCK_RATE: u64 = 4_194_304;
+/// Maximum emulated time for a blargg ROM (the full cpu_instrs takes about
+/// a minute).
+const BLARGG_TIMEOUT: u64 = 120 * CLOCK_RATE;
+/// Maximum emulated time for a mooneye ROM.
+const MOONEYE_TIMEOUT: u64 = 10 * CLOCK_RATE;
+/// Number of clocks between two checks of the blargg result.
+const BLARGG_CHECK_INTERVAL: u64 = 70224;
+
+/// `LD B,B`, which mooneye ROMs execute when they finish.
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+
+/// ROMs that are expected to fail, one path relative to `$GBR_TEST_ROMS` per
+/// line.
+const KNOWN_FAILURES: &str = include_str!("known_failures.txt");
+
+/// Outcome of a test ROM.
+enum Outcome {
+    Pass,
+    Fail(String),
+    Timeout,
+    Panic(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(ref msg) => write!(f, "FAIL    {}", msg),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+            Outcome::Panic(ref msg) => write!(f, "PANIC   {}", msg),
+        }
+    }
+}
+
+/// Returns the test ROMs under a subdirectory of `$GBR_TEST_ROMS`, or `None`
+/// if the directory is not available.
+fn find_roms(subdir: &str) -> Option<Vec<PathBuf>> {
+    let root = match env::var_os("GBR_TEST_ROMS") {
+        Some(root) => PathBuf::from(root).join(subdir),
+        None => {
+            eprintln!("GBR_TEST_ROMS is not set, skipping {}", subdir);
+            return None;
+        }
+    };
+
+    if !root.is_dir() {
+        eprintln!("{} does not exist, skipping", root.display());
+        return None;
+    }
+
+    let filter = env::var("GBR_TEST_FILTER").unwrap_or_default();
+
+    let mut roms = Vec::new();
+    collect_roms(&root, &mut roms);
+    roms.retain(|rom| rom.to_string_lossy().contains(&filter));
+    roms.sort();
+
+    Some(roms)
+}
+
+/// Returns whether a ROM is listed in `known_failures.txt`.
+fn is_known_failure(name: &str) -> bool {
+    KNOWN_FAILURES.lines().any(|line| line.trim() == name)
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| panic!("{}: {}", dir.display(), e));
+
+    for entry in entries {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            collect_roms(&path, roms);
+        } else if path.extension().map_or(false, |ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+fn load(rom: &Path) -> GameBoy {
+    let catridge = Catridge::new(&rom.to_string_lossy(), true)
+        .unwrap_or_else(|e| panic!("{}: {}", rom.display(), e));
+
+    GameBoy::new(catridge)
+}
+
+/// Returns the result reported by a blargg ROM, if it has finished. Results
+/// are written to memory at 0xa000 (status, then the signature de b0 61 and
+/// the text) and sent through the serial port.
+fn blargg_result(gameboy: &GameBoy, serial: &str) -> Option<Outcome> {
+    let signature = [
+        gameboy.peek(0xa001),
+        gameboy.peek(0xa002),
+        gameboy.peek(0xa003),
+    ];
+
+    if signature == [0xde, 0xb0, 0x61] {
+        let status = gameboy.peek(0xa000);
+        if status != 0x80 {
+            let text: String = (0xa004..0xbfff)
+                .map(|addr| gameboy.peek(addr))
+                .take_while(|&c| c != 0)
+                .map(|c| c as char)
+                .collect();
+
+            return Some(match status {
+                0 => Outcome::Pass,
+                _ => Outcome::Fail(text.trim().replace('\n', " ")),
+            });
+        }
+    }
+
+    if serial.contains("Passed") {
+        Some(Outcome::Pass)
+    } else if serial.contains("Failed") {
+        Some(Outcome::Fail(serial.trim().replace('\n', " ")))
+    } else {
+        None
+    }
+}
+
+fn run_blargg(rom: &Path) -> Outcome {
+    let mut gameboy = load(rom);
+    let mut serial = String::new();
+    let mut elapsed = 0;
+    let mut next_check = BLARGG_CHECK_INTERVAL;
+
+    while elapsed < BLARGG_TIMEOUT {
+        elapsed += gameboy.step() as u64;
+
+        if elapsed >= next_check {
+            next_check += BLARGG_CHECK_INTERVAL;
+
+            let output = gameboy.take_serial_output();
+            serial.extend(output.iter().map(|&c| c as char));
+
+            if let Some(outcome) = blargg_result(&gameboy, &serial) {
+                return outcome;
+            }
+        }
+    }
+
+    Outcome::Timeout
+}
+
+fn run_mooneye(rom: &Path) -> Outcome {
+    let mut gameboy = load(rom);
+    let mut elapsed = 0;
+
+    while elapsed < MOONEYE_TIMEOUT {
+        let pc = gameboy.cpu.registers().pc;
+        let breakpoint = gameboy.peek(pc) == MOONEYE_BREAKPOINT;
+
+        elapsed += gameboy.step() as u64;
+
+        if breakpoint {
+            let regs = gameboy.cpu.registers();
+            let values = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+
+            if values == [3, 5, 8, 13, 21, 34] {
+                return Outcome::Pass;
+            } else if values == [0x42; 6] {
+                return Outcome::Fail(String::from("failure signature"));
+            }
+        }
+    }
+
+    Outcome::Timeout
+}
+
+/// Runs ROMs, prints a table of their outcomes and fails if any ROM that is
+/// not a known failure did not pass.
+fn run_suite(subdir: &str, run: fn(&Path) -> Outcome) {
+    let roms = match find_roms(subdir) {
+        Some(roms) => roms,
+        None => return,
+    };
+
+    let outcomes: Vec<Outcome> = roms
+        .iter()
+        .map(
+            |rom| match panic::catch_unwind(AssertUnwindSafe(|| run(rom))) {
+                Ok(outcome) => outcome,
+                Err(e) => Outcome::Panic(
+                    e.downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_default(),
+                ),
+            },
+        )
+        .collect();
+
+    let root = PathBuf::from(env::var_os("GBR_TEST_ROMS").unwrap()).join(subdir);
+    let mut passed = 0;
+    let mut regressions = Vec::new();
+    let mut fixed = Vec::new();
+
+    println!();
+    for (rom, outcome) in roms.iter().zip(&outcomes) {
+        let name = rom.strip_prefix(&root).unwrap_or(rom);
+        // Known failures are listed with forward slashes on all platforms
+        let key = format!("{}/{}", subdir, name.to_string_lossy().replace('\\', "/"));
+        let known = is_known_failure(&key);
+
+        if known {
+            println!("{:<50} {} (known failure)", name.display(), outcome);
+        } else {
+            println!("{:<50} {}", name.display(), outcome);
+        }
+
+        match (outcome, known) {
+            (&Outcome::Pass, false) => passed += 1,
+            (&Outcome::Pass, true) => {
+                passed += 1;
+                fixed.push(key);
+            }
+            (_, false) => regressions.push(key),
+            (_, true) => (),
+        }
+    }
+    println!("{}: {}/{} passed", subdir, passed, roms.len());
+
+    if !fixed.is_empty() {
+        println!("Passed, remove from known_failures.txt:");
+        for key in &fixed {
+            println!("    {}", key);
+        }
+    }
+    if !regressions.is_empty() {
+        println!("Failed, not in known_failures.txt:");
+        for key in &regressions {
+            println!("    {}", key);
+        }
+    }
+
+    assert!(
+        regressions.is_empty(),
+        "{} ROMs failed unexpectedly",
+        regressions.len()
+    );
+}
+
+#[test]
+fn blargg_cpu_instrs() {
+    run_suite("blargg/cpu_instrs", run_blargg);
+}
+
+#[test]
+fn blargg_instr_timing() {
+    run_suite("blargg/instr_timing", run_blargg);
+}
+
+#[test]
+fn mooneye_acceptance() {
+    run_suite("mooneye/acceptance", run_mooneye);
+}
@@ -0,0 +1,207 @@
+//! Runs the SingleStepTests SM83 tests, which execute a single instruction
+//! from a given state and compare the registers, memory and cycle count with
+//! the expected state.
+//!
+//! The tests are not distributed with gbr. Point `GBR_SM83_TESTS` to the
+//! directory containing the JSON files (e.g. `v1/00.json`, `v1/cb 00.json`),
+//! otherwise the test is skipped. Set `GBR_TEST_FILTER` to only run files
+//! whose name contains a given string.
+
+extern crate gbr;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use gbr::bus::Bus;
+use gbr::cpu::{Registers, CPU};
+use serde_json::Value;
+
+/// Flat 64 KiB memory without any devices.
+struct FlatBus {
+    mem: Vec<u8>,
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.mem[addr as usize] = val;
+    }
+
+    fn tick(&mut self) {}
+
+    fn pending_interrupts(&self) -> u8 {
+        0
+    }
+
+    fn ack_interrupt(&mut self, _id: u8) {}
+}
+
+/// Returns a numeric field of a JSON object.
+fn field(state: &Value, key: &str) -> u16 {
+    state[key]
+        .as_u64()
+        .unwrap_or_else(|| panic!("Missing field: {}", key)) as u16
+}
+
+/// Returns the registers of a test state.
+fn registers(state: &Value) -> Registers {
+    Registers {
+        a: field(state, "a") as u8,
+        f: field(state, "f") as u8,
+        b: field(state, "b") as u8,
+        c: field(state, "c") as u8,
+        d: field(state, "d") as u8,
+        e: field(state, "e") as u8,
+        h: field(state, "h") as u8,
+        l: field(state, "l") as u8,
+        sp: field(state, "sp"),
+        pc: field(state, "pc"),
+    }
+}
+
+/// Returns the memory contents of a test state as (address, value) pairs.
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"]
+        .as_array()
+        .expect("Missing field: ram")
+        .iter()
+        .map(|entry| {
+            (
+                entry[0].as_u64().unwrap() as u16,
+                entry[1].as_u64().unwrap() as u8,
+            )
+        })
+        .collect()
+}
+
+/// Runs a single test and returns a description of the first mismatch, if any.
+fn run_test(test: &Value) -> Result<(), String> {
+    let initial = &test["initial"];
+    let expected = &test["final"];
+
+    let mut cpu = CPU::with_bus(FlatBus {
+        mem: vec![0; 0x10000],
+    });
+    cpu.set_registers(registers(initial));
+    cpu.set_ime(field(initial, "ime") > 0);
+    for (addr, val) in ram(initial) {
+        cpu.bus.write(addr, val);
+    }
+
+    let tick = cpu.step();
+
+    let regs = cpu.registers();
+    let expected_regs = registers(expected);
+    if regs != expected_regs {
+        return Err(format!(
+            "registers are {:x?}, expected {:x?}",
+            regs, expected_regs
+        ));
+    }
+
+    if let Some(ime) = expected["ime"].as_u64() {
+        if cpu.ime() != (ime > 0) {
+            return Err(format!("IME is {}, expected {}", cpu.ime(), ime > 0));
+        }
+    }
+
+    for (addr, val) in ram(expected) {
+        let actual = cpu.bus.read(addr);
+        if actual != val {
+            return Err(format!(
+                "0x{:04x} is 0x{:02x}, expected 0x{:02x}",
+                addr, actual, val
+            ));
+        }
+    }
+
+    let cycles = test["cycles"].as_array().map_or(0, |cycles| cycles.len());
+    if tick as usize != cycles * 4 {
+        return Err(format!("took {} clocks, expected {}", tick, cycles * 4));
+    }
+
+    Ok(())
+}
+
+/// Runs all tests in a file and returns the number of tests and failures
+/// along with the first failure.
+fn run_file(path: &Path) -> (usize, usize, Option<String>) {
+    let json = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let tests: Vec<Value> =
+        serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    let mut failed = 0;
+    let mut first_failure = None;
+
+    for test in &tests {
+        let res = match panic::catch_unwind(AssertUnwindSafe(|| run_test(test))) {
+            Ok(res) => res,
+            Err(_) => Err(String::from("panicked")),
+        };
+
+        if let Err(msg) = res {
+            failed += 1;
+            if first_failure.is_none() {
+                let name = test["name"].as_str().unwrap_or("?");
+                first_failure = Some(format!("{}: {}", name, msg));
+            }
+        }
+    }
+
+    (tests.len(), failed, first_failure)
+}
+
+#[test]
+fn sm83_single_step() {
+    let dir = match env::var_os("GBR_SM83_TESTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("GBR_SM83_TESTS is not set, skipping");
+            return;
+        }
+    };
+
+    let filter = env::var("GBR_TEST_FILTER").unwrap_or_default();
+
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .filter(|path| path.to_string_lossy().contains(&filter))
+        .collect();
+    files.sort();
+
+    let mut failed_files = 0;
+
+    println!();
+    for path in &files {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let (total, failed, first_failure) = run_file(path);
+
+        if failed == 0 {
+            println!("{:<8} PASS    {}/{}", name, total, total);
+        } else {
+            failed_files += 1;
+            println!(
+                "{:<8} FAIL    {}/{}  {}",
+                name,
+                total - failed,
+                total,
+                first_failure.unwrap()
+            );
+        }
+    }
+    println!(
+        "sm83: {}/{} opcodes passed",
+        files.len() - failed_files,
+        files.len()
+    );
+
+    assert_eq!(failed_files, 0, "{} opcodes failed", failed_files);
+}